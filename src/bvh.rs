@@ -1,125 +1,978 @@
 use crate::utility::*;
-use crate::hittable::Hittable;
+use crate::hittable::{Hittable, TrianglePacket, hit_leaf, occluded_leaf, triangle_positions};
+use crate::simd::{self, Lanes};
 use crate::material::MaterialId;
+use crate::mesh::{MeshId, TriangleId};
 use crate::render::SceneData;
+use crate::cache;
+use std::error::Error;
+use std::fmt;
 
 // ------------------------------------------- Bounding volume hieracrchy -------------------------------------------
 
 type NodeId = u32;
 type LeafId = u32;
 
-#[derive(Debug, Clone)]
-enum BvhNode {
-    Branch {aabb: AABB, left: NodeId, right: NodeId},
-    Leaf {aabb: AABB, leaf: LeafId},
+/// Deeper nodes are made into leaves so that the traversal stack never overflows
+const MAX_DEPTH: usize = 64;
+
+/// Largest number of hittables in a leaf, the count of a node is a u16
+const MAX_LEAF_COUNT: usize = u16::MAX as usize;
+
+/// The nodes are stored in depth-first order: the first child of a branch is right after it.
+/// The bounds are rounded outward to f32 so that a node fits in 32 bytes.
+#[derive(Debug, Clone, PartialEq)]
+struct BvhNode {
+    min: [f32; 3],
+    max: [f32; 3],
+    /// Branch: index of the second child. Leaf: index of the first hittable.
+    offset: u32,
+    /// Number of hittables of a leaf, zero for a branch
+    count: u16,
+    /// Axis along which the children of a branch were split
+    axis: u8,
 }
 
+const _: () = assert!(std::mem::size_of::<BvhNode>() == 32);
+
 impl BvhNode {
-    fn bounding_box(&self) -> &AABB {
-        match self {
-            Self::Leaf {aabb, ..} => aabb,
-            Self::Branch {aabb, ..} => aabb,
+    fn new(aabb: &AABB, offset: u32, count: u16, axis: u8) -> Self {
+        let round_down = |x: Real| {let y = x as f32; if y as Real > x {y.next_down()} else {y}};
+        let round_up = |x: Real| {let y = x as f32; if (y as Real) < x {y.next_up()} else {y}};
+        BvhNode {
+            min: [round_down(aabb.min.x), round_down(aabb.min.y), round_down(aabb.min.z)],
+            max: [round_up(aabb.max.x), round_up(aabb.max.y), round_up(aabb.max.z)],
+            offset, count, axis,
+        }
+    }
+
+    fn bounding_box(&self) -> AABB {
+        AABB {
+            min: vector![self.min[0] as Real, self.min[1] as Real, self.min[2] as Real],
+            max: vector![self.max[0] as Real, self.max[1] as Real, self.max[2] as Real],
+        }
+    }
+}
+
+/// A node of the tree with up to four children, whose bounds are tested at once
+#[derive(Debug, Clone, Default)]
+#[repr(align(64))]
+struct WideNode {
+    /// Bounds of the children, `min[axis][child]`
+    min: [[f32; 4]; 3],
+    max: [[f32; 4]; 3],
+    /// Branch: index of the child node. Leaf: index of the first hittable.
+    offset: [u32; 4],
+    /// Number of hittables of a leaf, zero for a branch
+    count: [u16; 4],
+    num_children: u8,
+}
+
+const _: () = assert!(std::mem::size_of::<WideNode>() == 128);
+
+/// Make a tree with four children per node from the binary tree, by replacing the largest branches with their
+/// children. The root of a tree that is only a leaf is a node with a single child.
+fn collapse(nodes: &[BvhNode]) -> Vec<WideNode> {
+    fn collapse_node(nodes: &[BvhNode], node_id: usize, wide_nodes: &mut Vec<WideNode>) -> u32 {
+        let mut children = if nodes[node_id].count > 0 {
+            vec![node_id]
+        } else {
+            vec![node_id + 1, nodes[node_id].offset as usize]
+        };
+        while children.len() < 4 {
+            let largest = children.iter().enumerate().filter(|(_, x)| nodes[**x].count == 0)
+                .map(|(i, x)| (i, nodes[*x].bounding_box().surface_area()))
+                .reduce(|a, b| if b.1 > a.1 {b} else {a});
+            match largest {
+                Some((i, _)) => {
+                    let branch = children[i];
+                    children[i] = branch + 1;
+                    children.push(nodes[branch].offset as usize);
+                }
+                None => break,
+            }
+        }
+
+        let wide_id = wide_nodes.len();
+        wide_nodes.push(WideNode {num_children: children.len() as u8, ..WideNode::default()});
+        for (i, &child) in children.iter().enumerate() {
+            let node = &nodes[child];
+            let offset = if node.count > 0 {node.offset} else {collapse_node(nodes, child, wide_nodes)};
+            let wide_node = &mut wide_nodes[wide_id];
+            for axis in 0..3 {
+                wide_node.min[axis][i] = node.min[axis];
+                wide_node.max[axis][i] = node.max[axis];
+            }
+            wide_node.offset[i] = offset;
+            wide_node.count[i] = node.count;
         }
+        wide_id as u32
+    }
+
+    let mut wide_nodes = Vec::new();
+    if !nodes.is_empty() {
+        collapse_node(nodes, 0, &mut wide_nodes);
     }
+    wide_nodes
 }
 
 #[derive(Clone)]
 pub struct Bvh {
     /// Content of the leaf nodes to be indexed by LeafId
     leaves: Vec<Hittable>,
-    /// Tree structure to be index by NodeId
+    /// Tree structure to be index by NodeId, the root is the first node
     nodes: Vec<BvhNode>,
-    /// Id of the root node
-    root: NodeId,
+    /// The same tree with four children per node, to hit rays faster. See `collapse`.
+    wide_nodes: Vec<WideNode>,
+    /// The corners of the runs of triangles of the leaves, in the order of the hittables. See `make_packets`.
+    packets: Vec<TrianglePacket>,
+    /// Number of packets of the runs that start before each hittable, the packets of the hittables `a..b` of a leaf
+    /// are `packets[packet_starts[a]..packet_starts[b]]`
+    packet_starts: Vec<u32>,
+    /// To rebuild the tree the same way
+    strategy: BvhStrategy,
+    /// SAH cost of the tree when it was built, to know how much the refits degraded it
+    build_cost: Real,
 }
 
-fn make_bvh(content: &mut [(LeafId, AABB)], sort_axis: usize, nodes: &mut Vec<BvhNode>) 
-    -> NodeId
+/// How to split the hittables into the two children of a node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BvhStrategy {
+    /// Sort the centroids along the x, y, z axes in turn and split at the median. One hittable per leaf.
+    Median,
+    /// Choose the split with the lowest surface area heuristic cost among bins of centroids, along all axes
+    // https://www.pbr-book.org/3ed-2018/Primitives_and_Intersection_Acceleration/Bounding_Volume_Hierarchies
+    Sah {num_bins: usize, max_leaf_size: usize},
+}
+
+impl Default for BvhStrategy {
+    fn default() -> Self {
+        BvhStrategy::Sah {num_bins: 16, max_leaf_size: 4}
+    }
+}
+
+/// Cost of traversing a branch relative to the cost of hitting a hittable
+const TRAVERSAL_COST: Real = 0.5;
+
+/// A refitted tree that is this much slower than when it was built should rather be rebuilt
+const REBUILD_DEGRADATION: Real = 1.5;
+
+/// Smaller nodes are built by a single thread, spawning more would cost more than it saves
+const PARALLEL_THRESHOLD: usize = 4096;
+
+/// Gather the corners of the runs of triangles of each leaf, see `TrianglePacket`. `positions` gives the corners of a
+/// triangle. The leaves must not share hittables.
+fn make_packets(leaves: &[Hittable], nodes: &[BvhNode], positions: impl Fn(TriangleId, MeshId) -> (Rvec3, Rvec3, Rvec3))
+    -> (Vec<TrianglePacket>, Vec<u32>)
 {
-    match content.len() {
-        0 => unreachable!(),
-        1 => {
-            let (leaf, aabb) = content[0].clone();
-            nodes.push(BvhNode::Leaf {leaf, aabb});
-            (nodes.len() - 1) as NodeId
+    let mut packets = nodes.iter().filter(|node| node.count > 0).flat_map(|node| {
+        let first = node.offset as usize;
+        TrianglePacket::of_leaf(&leaves[first..first + node.count as usize], &positions).into_iter()
+            .map(move |(start, packet)| (first + start, packet))
+    }).collect::<Vec<_>>();
+    packets.sort_unstable_by_key(|(start, _)| *start);
+
+    let mut packet_starts = Vec::with_capacity(leaves.len() + 1);
+    let mut num_packets = 0;
+    for hittable in 0..=leaves.len() {
+        while num_packets < packets.len() && packets[num_packets].0 < hittable {
+            num_packets += 1;
         }
-        _ => {
-            let (left_content, right_content) = split(content, sort_axis);
-            let left = make_bvh(left_content, (sort_axis + 1) % 3, nodes);
-            let right = make_bvh(right_content, (sort_axis + 1) % 3, nodes);
-            let aabb = nodes[left as usize].bounding_box()
-                .union(nodes[right as usize].bounding_box());
-            nodes.push(BvhNode::Branch {left, right, aabb});
-            (nodes.len() - 1) as NodeId
+        packet_starts.push(num_packets as u32);
+    }
+    (packets.into_iter().map(|(_, packet)| packet).collect(), packet_starts)
+}
+
+/// The corners of the triangles of the meshes of the scene
+fn scene_positions(scene_data: &SceneData) -> impl Fn(TriangleId, MeshId) -> (Rvec3, Rvec3, Rvec3) + '_ {
+    move |triangle, mesh| triangle_positions(triangle, &scene_data.mesh_table[mesh.to_index()])
+}
+
+/// Apply a function to the chunks of a slice on several threads, the results are in the order of the chunks.
+/// Everything that is built with it does not depend on the number of threads.
+fn map_chunks<T: Sync, R: Send>(items: &[T], num_threads: usize, f: impl Fn(&[T]) -> R + Sync) -> Vec<R> {
+    if num_threads <= 1 || items.len() < PARALLEL_THRESHOLD {
+        return vec![f(items)]
+    }
+    let f = &f;
+    std::thread::scope(|scope| {
+        let chunks = items.chunks(items.len().div_ceil(num_threads))
+            .map(|chunk| scope.spawn(move || f(chunk)))
+            .collect::<Vec<_>>();
+        chunks.into_iter().map(|chunk| chunk.join().unwrap()).collect()
+    })
+}
+
+/// Append a tree that was built in its own vector after the nodes
+fn append_subtree(nodes: &mut Vec<BvhNode>, subtree: Vec<BvhNode>) {
+    let base = nodes.len() as u32;
+    nodes.extend(subtree.into_iter().map(|mut node| {
+        if node.count == 0 {
+            node.offset += base; // The leaves refer to hittables, they do not move
         }
+        node
+    }));
+}
+
+fn num_build_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+fn make_leaf(content: &[(LeafId, AABB)], first: LeafId, nodes: &mut Vec<BvhNode>) -> AABB {
+    let aabb = content.iter().fold(AABB::empty(), |acc, (_, aabb)| acc.union(aabb));
+    assert!(content.len() <= MAX_LEAF_COUNT, "The leaves are split until they fit in a node");
+    nodes.push(BvhNode::new(&aabb, first, content.len() as u16, 0));
+    aabb
+}
+
+/// Append the node of the hittables of `content`, which start at index `first` in the final list of leaves.
+/// The tree is the same whatever the number of threads.
+fn make_bvh(content: &mut [(LeafId, AABB)], first: LeafId, depth: usize, strategy: BvhStrategy, num_threads: usize,
+    nodes: &mut Vec<BvhNode>) -> AABB
+{
+    if content.len() == 1 || depth + 1 >= MAX_DEPTH {
+        return make_leaf(content, first, nodes)
     }
+    // The leaf forced at the maximum depth must fit in a node, so the content is halved when the levels left would
+    // not be enough otherwise
+    let levels_left = (MAX_DEPTH - depth - 1).min(usize::BITS as usize - 1);
+    let must_halve = (content.len() - 1) >> levels_left >= MAX_LEAF_COUNT;
+    let (split_at, axis) = match strategy {
+        _ if must_halve => (split_median(content, depth % 3), depth % 3),
+        BvhStrategy::Median => (split_median(content, depth % 3), depth % 3),
+        BvhStrategy::Sah {num_bins, max_leaf_size} => {
+            match split_sah(content, num_bins, max_leaf_size.min(MAX_LEAF_COUNT), num_threads) {
+                Some(split) => split,
+                None => return make_leaf(content, first, nodes),
+            }
+        }
+    };
+
+    // Reserve the branch, then append the children after it
+    let node = nodes.len();
+    nodes.push(BvhNode::new(&AABB::empty(), 0, 0, 0));
+    let parallel = num_threads > 1 && content.len() >= PARALLEL_THRESHOLD;
+    let (left_content, right_content) = content.split_at_mut(split_at);
+    let right_first = first + split_at as LeafId;
+    let (left_aabb, right, right_aabb) = if parallel {
+        // Build the children on two groups of threads
+        let left_threads = num_threads / 2;
+        let ((left_aabb, left_nodes), (right_aabb, right_nodes)) = std::thread::scope(|scope| {
+            let left = scope.spawn(|| {
+                let mut left_nodes = Vec::new();
+                let left_aabb = make_bvh(left_content, first, depth + 1, strategy, left_threads, &mut left_nodes);
+                (left_aabb, left_nodes)
+            });
+            let mut right_nodes = Vec::new();
+            let right_aabb = make_bvh(right_content, right_first, depth + 1, strategy, num_threads - left_threads,
+                &mut right_nodes);
+            (left.join().unwrap(), (right_aabb, right_nodes))
+        });
+        append_subtree(nodes, left_nodes);
+        let right = nodes.len() as NodeId;
+        append_subtree(nodes, right_nodes);
+        (left_aabb, right, right_aabb)
+    } else {
+        let left_aabb = make_bvh(left_content, first, depth + 1, strategy, 1, nodes);
+        let right = nodes.len() as NodeId;
+        let right_aabb = make_bvh(right_content, right_first, depth + 1, strategy, 1, nodes);
+        (left_aabb, right, right_aabb)
+    };
+    let aabb = left_aabb.union(&right_aabb);
+    nodes[node] = BvhNode::new(&aabb, right, 0, axis as u8);
+    aabb
 }
 
-fn split(content: &mut [(LeafId, AABB)], sort_axis: usize) -> (&mut [(LeafId, AABB)], &mut [(LeafId, AABB)]) {
+fn split_median(content: &mut [(LeafId, AABB)], sort_axis: usize) -> usize {
     // Sort by bounding box centroid
     content.sort_unstable_by(|(_, x_bb), (_, y_bb)| {
-        let x_center = 0.5 * (x_bb.min[sort_axis] + x_bb.max[sort_axis]);
-        let y_center = 0.5 * (y_bb.min[sort_axis] + y_bb.max[sort_axis]);
-        x_center.partial_cmp(&y_center).unwrap()
+        x_bb.center()[sort_axis].total_cmp(&y_bb.center()[sort_axis])
     });
-    // Split at the median without allocating a new vector
-    content.split_at_mut(content.len() / 2)
+    content.len() / 2
+}
+
+/// Reorder the content around the best split and return where to split it and along which axis, or None to make a
+/// leaf
+fn split_sah(content: &mut [(LeafId, AABB)], num_bins: usize, max_leaf_size: usize, num_threads: usize)
+    -> Option<(usize, usize)>
+{
+    // Bounds of the boxes and of their centroids. Unions are exact, so they do not depend on the chunks.
+    let (aabb, centroid_bounds) = map_chunks(content, num_threads, |chunk| chunk.iter().fold(
+        (AABB::empty(), AABB::empty()),
+        |(aabb, centroids), (_, x)| (aabb.union(x), centroids.union_point(&x.center()))
+    )).into_iter().reduce(|a, b| (a.0.union(&b.0), a.1.union(&b.1))).unwrap();
+    let extent = centroid_bounds.max - centroid_bounds.min;
+    let bin_of = |aabb: &AABB, axis: usize| {
+        let offset = (aabb.center()[axis] - centroid_bounds.min[axis]) / extent[axis];
+        ((offset * num_bins as Real) as usize).min(num_bins - 1)
+    };
+
+    // Count the content of the bins of each axis
+    let all_bins = map_chunks(content, num_threads, |chunk| {
+        let mut bins = vec![vec![(0, AABB::empty()); num_bins]; 3];
+        for (_, aabb) in chunk.iter() {
+            for (axis, axis_bins) in bins.iter_mut().enumerate().filter(|(axis, _)| extent[*axis] > 0.0) {
+                let bin = &mut axis_bins[bin_of(aabb, axis)];
+                bin.0 += 1;
+                bin.1 = bin.1.union(aabb);
+            }
+        }
+        bins
+    }).into_iter().reduce(|mut a, b| {
+        for (a, b) in a.iter_mut().flatten().zip(b.iter().flatten()) {
+            *a = (a.0 + b.0, a.1.union(&b.1));
+        }
+        a
+    }).unwrap();
+
+    // Find the cheapest split between two bins
+    let mut best = None;
+    for (axis, bins) in all_bins.iter().enumerate() {
+        if extent[axis] <= 0.0 {
+            continue
+        }
+
+        // Sweep from the right to know the cost of the right side of each split
+        let mut right_costs = vec![0.0; num_bins];
+        let (mut count, mut bounds) = (0, AABB::empty());
+        for bin in (1..num_bins).rev() {
+            count += bins[bin].0;
+            bounds = bounds.union(&bins[bin].1);
+            right_costs[bin] = count as Real * bounds.surface_area();
+        }
+        // Then from the left, the split is between `bin - 1` and `bin`
+        let (mut count, mut bounds) = (0, AABB::empty());
+        for bin in 1..num_bins {
+            count += bins[bin - 1].0;
+            bounds = bounds.union(&bins[bin - 1].1);
+            let cost = count as Real * bounds.surface_area() + right_costs[bin];
+            if count > 0 && count < content.len() && best.is_none_or(|(best_cost, _, _)| cost < best_cost) {
+                best = Some((cost, axis, bin));
+            }
+        }
+    }
+
+    // Compare with the cost of not splitting
+    let leaf_cost = content.len() as Real;
+    match best {
+        Some((cost, axis, bin)) => {
+            let cost = TRAVERSAL_COST + cost / aabb.surface_area();
+            if cost >= leaf_cost && content.len() <= max_leaf_size {
+                return None
+            }
+            // Move the content of the left bins to the front
+            let mut split_at = 0;
+            for i in 0..content.len() {
+                if bin_of(&content[i].1, axis) < bin {
+                    content.swap(i, split_at);
+                    split_at += 1;
+                }
+            }
+            Some((split_at, axis))
+        }
+        // All the centroids are at the same place
+        None if content.len() <= max_leaf_size => None,
+        None => Some((content.len() / 2, 0)),
+    }
 }
 
 impl Bvh {
-    pub fn new(hittables: Vec<Hittable>, scene_data: &SceneData) -> Self {
-        let mut content = hittables.iter().enumerate().map(|(id, x)| (id as LeafId, x.bounding_box(scene_data)))
+    pub fn new(hittables: Vec<Hittable>, scene_data: &SceneData, strategy: BvhStrategy) -> Self {
+        let bounding_boxes = map_chunks(&hittables, num_build_threads(), |chunk| {
+            chunk.iter().map(|x| x.bounding_box(scene_data)).collect::<Vec<_>>()
+        }).concat();
+        Self::with_bounding_boxes(hittables, bounding_boxes, strategy, scene_positions(scene_data))
+    }
+
+    /// Build the BVH when the bounding boxes of the hittables are already known, for the trees that are not in the
+    /// scene data yet. `positions` gives the corners of the triangles.
+    pub fn with_bounding_boxes(hittables: Vec<Hittable>, bounding_boxes: Vec<AABB>, strategy: BvhStrategy,
+        positions: impl Fn(TriangleId, MeshId) -> (Rvec3, Rvec3, Rvec3)) -> Self
+    {
+        let mut content = bounding_boxes.into_iter().enumerate().map(|(id, aabb)| (id as LeafId, aabb))
             .collect::<Vec<_>>();
         
         let mut nodes = Vec::new();
-        let root = make_bvh(&mut content, 0, &mut nodes);
-
-        // nodes.iter().enumerate().for_each(|(id, n)| match n {
-        //     BvhNode::Leaf {..} => println!("#{}: Leaf ({:?})", id, content[id].1),
-        //     BvhNode::Branch {left, right, ..} => println!("#{}: Branch (#{}, #{})", id, left, right),
-        // });
-        // println!("Recap: {} branches, {} leaves",
-        //     nodes.iter().filter(|n| matches!(n, BvhNode::Branch {..})).count(),
-        //     nodes.iter().filter(|n| matches!(n, BvhNode::Leaf {..})).count(),
-        // );
-        // println!("{}", std::mem::size_of::<BvhNode>());
-        
-        Bvh {
-            leaves: hittables,
-            nodes, root
+        if !content.is_empty() {
+            make_bvh(&mut content, 0, 0, strategy, num_build_threads(), &mut nodes);
         }
+
+        // Put the hittables in the order of the leaves so that each leaf refers to a range of them
+        let mut hittables = hittables.into_iter().map(Some).collect::<Vec<_>>();
+        let leaves = content.iter().map(|(id, _)| hittables[*id as usize].take().unwrap())
+            .collect::<Vec<_>>();
+
+        let wide_nodes = collapse(&nodes);
+        let (packets, packet_starts) = make_packets(&leaves, &nodes, positions);
+        let mut bvh = Bvh {leaves, nodes, wide_nodes, packets, packet_starts, strategy, build_cost: 0.0};
+        bvh.build_cost = bvh.sah_cost();
+        bvh
     }
 
-    fn hit_node(&self, ray: &RayExpanded, node: NodeId, scene_data: &SceneData) -> Option<(Hit, MaterialId)> {
-        match &self.nodes[node as usize] {
-            BvhNode::Leaf {aabb, leaf} => {
-                if aabb.collide(ray) {
-                    self.leaves[*leaf as usize].hit(&ray.inner, scene_data)
-                } else {
-                    None
+    /// Build again the tree of the same hittables, after they moved. It is cheap for the top level of a scene,
+    /// whose leaves are a few instances of meshes.
+    pub fn rebuild(&mut self, scene_data: &SceneData) {
+        let leaves = std::mem::take(&mut self.leaves);
+        *self = Self::new(leaves, scene_data, self.strategy);
+    }
+
+    /// The hittables stored in the leaves, in no particular order
+    pub fn leaves(&self) -> &[Hittable] {
+        &self.leaves
+    }
+
+    /// Recompute the bounding boxes of the nodes after the hittables moved, without changing the tree.
+    /// Each frame of an animation, it is much faster than a rebuild, but the tree slowly degrades.
+    pub fn refit(&mut self, scene_data: &SceneData) {
+        self.refit_with(|x| x.bounding_box(scene_data), scene_positions(scene_data))
+    }
+
+    /// Refit with the given bounding boxes of the hittables and corners of the triangles, for the trees that are not
+    /// in the scene data yet
+    pub fn refit_with(&mut self, bounding_box: impl Fn(&Hittable) -> AABB,
+        positions: impl Fn(TriangleId, MeshId) -> (Rvec3, Rvec3, Rvec3))
+    {
+        // The children come after their parent, so walking backward refits the children first
+        for node_id in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[node_id];
+            let aabb = if node.count > 0 {
+                let first = node.offset as usize;
+                self.leaves[first..first + node.count as usize].iter()
+                    .fold(AABB::empty(), |acc, x| acc.union(&bounding_box(x)))
+            } else {
+                self.nodes[node_id + 1].bounding_box().union(&self.nodes[node.offset as usize].bounding_box())
+            };
+            let node = &mut self.nodes[node_id];
+            *node = BvhNode::new(&aabb, node.offset, node.count, node.axis);
+        }
+        self.wide_nodes = collapse(&self.nodes);
+        (self.packets, self.packet_starts) = make_packets(&self.leaves, &self.nodes, positions);
+    }
+
+    /// Expected number of hittables and branches hit by a ray that hits the root, weighted by their cost.
+    /// The surface area of a node is proportional to the probability that a ray hits it.
+    pub fn sah_cost(&self) -> Real {
+        let root_area = self.bounding_box().surface_area();
+        if root_area <= 0.0 {
+            return self.leaves.len() as Real
+        }
+        self.nodes.iter().map(|node| {
+            let cost = if node.count > 0 {node.count as Real} else {TRAVERSAL_COST};
+            cost * node.bounding_box().surface_area() / root_area
+        }).sum()
+    }
+
+    /// How many times slower the tree became since it was built, 1 means that it is as good as new
+    pub fn degradation(&self) -> Real {
+        if self.build_cost > 0.0 {self.sah_cost() / self.build_cost} else {1.0}
+    }
+
+    /// Whether the refits degraded the tree so much that rebuilding it is worth it
+    pub fn needs_rebuild(&self) -> bool {
+        self.degradation() > REBUILD_DEGRADATION
+    }
+
+    pub fn strategy(&self) -> BvhStrategy {
+        self.strategy
+    }
+
+    /// The bounding box of the root node
+    pub fn bounding_box(&self) -> AABB {
+        self.nodes.first().map_or(AABB::empty(), |node| node.bounding_box())
+    }
+
+    pub fn hit(&self, ray: &Ray, scene_data: &SceneData) -> Option<(Hit, MaterialId)> {
+        let mut hit = None;
+        self.traverse(ray, |leaf, packets, ray| {
+            if let Some(new_hit) = hit_leaf(leaf, packets, ray, scene_data) {
+                ray.t_max = new_hit.0.t;
+                hit.replace(new_hit);
+            }
+            false
+        });
+        hit
+    }
+
+    /// Whether the ray hits anything, the traversal stops at the first hit found
+    pub fn occluded(&self, ray: &Ray, scene_data: &SceneData) -> bool {
+        self.traverse(ray, |leaf, packets, ray| occluded_leaf(leaf, packets, ray, scene_data))
+    }
+
+    /// Visit the leaves that the ray enters, nearest first, with their packets of triangles. `visit_leaf` may shorten
+    /// the ray, and returns true to stop the traversal. Returns whether the traversal was stopped.
+    fn traverse(&self, ray: &Ray, visit_leaf: impl FnMut(&[Hittable], &[TrianglePacket], &mut Ray) -> bool)
+        -> bool
+    {
+        match simd::detect() {
+            // Safety: the processor supports AVX
+            Some(avx) => unsafe {self.traverse_avx(avx, ray, visit_leaf)},
+            None => self.traverse_with(simd::Scalar, ray, visit_leaf),
+        }
+    }
+
+    /// The whole traversal is compiled for AVX so that the box tests are inlined
+    #[target_feature(enable = "avx")]
+    #[cfg(target_arch = "x86_64")]
+    unsafe fn traverse_avx(&self, avx: simd::Avx, ray: &Ray,
+        visit_leaf: impl FnMut(&[Hittable], &[TrianglePacket], &mut Ray) -> bool) -> bool
+    {
+        self.traverse_with(avx, ray, visit_leaf)
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    unsafe fn traverse_avx(&self, avx: simd::Avx, ray: &Ray,
+        visit_leaf: impl FnMut(&[Hittable], &[TrianglePacket], &mut Ray) -> bool) -> bool
+    {
+        self.traverse_with(avx, ray, visit_leaf)
+    }
+
+    #[inline(always)]
+    fn traverse_with(&self, lanes: impl Lanes, ray: &Ray,
+        mut visit_leaf: impl FnMut(&[Hittable], &[TrianglePacket], &mut Ray) -> bool) -> bool
+    {
+        let mut ray = ray.clone().expand();
+
+        // Nodes to visit with the distance at which the ray enters them, each node pushes up to three children
+        let mut stack = [(0 as NodeId, 0.0); 3 * MAX_DEPTH];
+        let mut stack_size = 0;
+        let mut next = if self.wide_nodes.is_empty() {None} else {Some((0, ray.inner.t_min))};
+
+        loop {
+            let (node_id, t_enter) = match next.take() {
+                Some(node) => node,
+                None if stack_size > 0 => {
+                    stack_size -= 1;
+                    stack[stack_size]
                 }
-            },
-            BvhNode::Branch {aabb, left, right} => {
-                if aabb.collide(ray) {
-                    let mut hit = None;
-                    let mut ray = ray.clone();
-                    if let Some(new_hit) = self.hit_node(&ray, *left, scene_data) {
-                        ray.inner.t_max = new_hit.0.t;
-                        hit.replace(new_hit);
-                    }
-                    if let Some(new_hit) = self.hit_node(&ray, *right, scene_data) {
-                        hit.replace(new_hit);
-                    }
-                    hit
+                None => return false,
+            };
+            // A closer hit was found since the node was pushed
+            if t_enter > ray.inner.t_max {
+                continue
+            }
+
+            let node = &self.wide_nodes[node_id as usize];
+            count_box_tests(node.num_children as u64);
+            let (mask, t) = lanes.collide4(&node.min, &node.max, &ray);
+            let mask = mask & ((1 << node.num_children) - 1);
+
+            // Sort the children that are hit from the nearest to the farthest
+            let mut children = [(0, 0.0); 4];
+            let mut num_hits = 0;
+            for child in (0..4).filter(|child| mask & (1 << child) != 0) {
+                let mut i = num_hits;
+                while i > 0 && children[i - 1].1 > t[child] {
+                    children[i] = children[i - 1];
+                    i -= 1;
+                }
+                children[i] = (child, t[child]);
+                num_hits += 1;
+            }
+
+            // Visit the leaves right away, then the nearest branch and push the others
+            for &(child, t_child) in children[..num_hits].iter() {
+                let (first, count) = (node.offset[child] as usize, node.count[child] as usize);
+                if count == 0 || t_child > ray.inner.t_max {
+                    continue
+                }
+                let packets = self.packet_starts[first] as usize..self.packet_starts[first + count] as usize;
+                if visit_leaf(&self.leaves[first..first + count], &self.packets[packets], &mut ray.inner) {
+                    return true
+                }
+            }
+            for &(child, t_child) in children[..num_hits].iter().rev().filter(|(child, _)| node.count[*child] == 0) {
+                if let Some(far) = next.replace((node.offset[child], t_child)) {
+                    stack[stack_size] = far;
+                    stack_size += 1;
+                }
+            }
+        }
+    }
+}
+
+// ------------------------------------------- Binary cache -------------------------------------------
+
+impl Bvh {
+    /// Encode the tree to be reloaded without building it, `write_leaf` encodes a hittable
+    pub(crate) fn write_cache(&self, writer: &mut cache::Writer, write_leaf: impl Fn(&Hittable, &mut cache::Writer)) {
+        match self.strategy {
+            BvhStrategy::Median => writer.u8(0),
+            BvhStrategy::Sah {num_bins, max_leaf_size} => {
+                writer.u8(1);
+                writer.u32(num_bins as u32);
+                writer.u32(max_leaf_size as u32);
+            }
+        }
+        writer.real(self.build_cost);
+        writer.len(self.leaves.len());
+        self.leaves.iter().for_each(|x| write_leaf(x, writer));
+        writer.len(self.nodes.len());
+        for node in self.nodes.iter() {
+            node.min.iter().chain(node.max.iter()).for_each(|x| writer.f32(*x));
+            writer.u32(node.offset);
+            writer.u16(node.count);
+            writer.u8(node.axis);
+        }
+    }
+
+    /// Decode a tree encoded by `write_cache`, `read_leaf` decodes a hittable and `positions` gives the corners of
+    /// the triangles. The structure of the tree is checked so that a damaged file cannot make the traversal go out of
+    /// bounds.
+    pub(crate) fn read_cache(reader: &mut cache::Reader,
+        read_leaf: impl Fn(&mut cache::Reader) -> Result<Hittable, Box<dyn Error>>,
+        positions: impl Fn(TriangleId, MeshId) -> (Rvec3, Rvec3, Rvec3)) -> Result<Self, Box<dyn Error>>
+    {
+        let strategy = match reader.u8()? {
+            0 => BvhStrategy::Median,
+            1 => BvhStrategy::Sah {num_bins: reader.u32()? as usize, max_leaf_size: reader.u32()? as usize},
+            _ => return Err("Unknown BVH strategy in the cache file".into()),
+        };
+        let build_cost = reader.real()?;
+        let leaves = (0..reader.len(1)?).map(|_| read_leaf(reader)).collect::<Result<Vec<_>, _>>()?;
+        let mut nodes = Vec::with_capacity(reader.len(31)?);
+        for _ in 0..nodes.capacity() {
+            let mut bounds = [0.0; 6];
+            for x in bounds.iter_mut() {
+                *x = reader.f32()?;
+            }
+            let (offset, count, axis) = (reader.u32()?, reader.u16()?, reader.u8()?);
+            nodes.push(BvhNode {min: [bounds[0], bounds[1], bounds[2]], max: [bounds[3], bounds[4], bounds[5]],
+                offset, count, axis});
+        }
+
+        // The children come after their parent, so the depths are known before reaching the children. Each node
+        // but the root must be the child of exactly one branch, so that the nodes form a tree.
+        let mut depths = vec![None; nodes.len()];
+        let mut in_leaf = vec![false; leaves.len()];
+        if let Some(root_depth) = depths.first_mut() {
+            *root_depth = Some(0);
+        }
+        for (node_id, node) in nodes.iter().enumerate() {
+            let depth = depths[node_id].ok_or("Unreachable BVH node in the cache file")?;
+            let hittables = node.offset as usize..node.offset as usize + node.count as usize;
+            let valid = if node.count > 0 {
+                // The leaves do not share hittables, so that each run of triangles has a single packet
+                hittables.end <= leaves.len() && !in_leaf[hittables.clone()].iter().any(|x| *x)
+            } else {
+                let second = node.offset as usize;
+                second > node_id + 1 && second < nodes.len() && node.axis < 3 && depth + 1 < MAX_DEPTH
+                    && depths[node_id + 1].is_none() && depths[second].is_none()
+            };
+            if !valid {
+                return Err("Invalid BVH node in the cache file".into())
+            }
+            if node.count > 0 {
+                in_leaf[hittables].iter_mut().for_each(|x| *x = true);
+            } else {
+                depths[node_id + 1] = Some(depth + 1);
+                depths[node.offset as usize] = Some(depth + 1);
+            }
+        }
+        if nodes.is_empty() != leaves.is_empty() {
+            return Err("Invalid BVH in the cache file".into())
+        }
+        let wide_nodes = collapse(&nodes);
+        let (packets, packet_starts) = make_packets(&leaves, &nodes, positions);
+        Ok(Bvh {leaves, nodes, wide_nodes, packets, packet_starts, strategy, build_cost})
+    }
+}
+
+// ------------------------------------------- Statistics -------------------------------------------
+
+#[derive(Debug, Clone, Default)]
+pub struct BvhStats {
+    pub num_branches: usize,
+    pub num_leaves: usize,
+    pub num_hittables: usize,
+    /// Number of leaves at each depth, the root is at depth 0
+    pub depth_histogram: Vec<usize>,
+    /// Number of leaves of each size
+    pub leaf_size_histogram: Vec<usize>,
+    pub sah_cost: Real,
+    pub degradation: Real,
+}
+
+impl Bvh {
+    pub fn stats(&self) -> BvhStats {
+        let mut stats = BvhStats {
+            num_hittables: self.leaves.len(),
+            sah_cost: self.sah_cost(),
+            degradation: self.degradation(),
+            ..BvhStats::default()
+        };
+        let mut stack = if self.nodes.is_empty() {vec![]} else {vec![(0, 0)]};
+        while let Some((node_id, depth)) = stack.pop() {
+            let node: &BvhNode = &self.nodes[node_id as usize];
+            if node.count > 0 {
+                let histogram_add = |histogram: &mut Vec<usize>, x: usize| {
+                    histogram.resize(histogram.len().max(x + 1), 0);
+                    histogram[x] += 1;
+                };
+                stats.num_leaves += 1;
+                histogram_add(&mut stats.depth_histogram, depth);
+                histogram_add(&mut stats.leaf_size_histogram, node.count as usize);
+            } else {
+                stats.num_branches += 1;
+                stack.push((node_id + 1, depth + 1));
+                stack.push((node.offset, depth + 1));
+            }
+        }
+        stats
+    }
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mean = |histogram: &[usize]| {
+            let total = histogram.iter().sum::<usize>().max(1) as Real;
+            histogram.iter().enumerate().map(|(x, n)| (x * n) as Real).sum::<Real>() / total
+        };
+        let format_histogram = |histogram: &[usize]| histogram.iter().enumerate().filter(|(_, n)| **n > 0)
+            .map(|(x, n)| format!("{}:{}", x, n)).collect::<Vec<_>>().join(" ");
+        writeln!(f, "{} hittables, {} branches, {} leaves", self.num_hittables, self.num_branches, self.num_leaves)?;
+        writeln!(f, "SAH cost {:.2}, {:.2} times the cost when built", self.sah_cost, self.degradation)?;
+        writeln!(f, "Leaf depth: mean {:.1}, max {}", mean(&self.depth_histogram),
+            self.depth_histogram.len().saturating_sub(1))?;
+        writeln!(f, "  {}", format_histogram(&self.depth_histogram))?;
+        writeln!(f, "Leaf size: mean {:.2}", mean(&self.leaf_size_histogram))?;
+        write!(f, "  {}", format_histogram(&self.leaf_size_histogram))
+    }
+}
+
+// ------------------------------------------- Traversal counters -------------------------------------------
+
+// The work done by each thread to hit rays, to make a heatmap of the cost of the traversal. They are only counted with
+// the `heatmap` feature, so that the other renders do not pay for them.
+#[cfg(feature = "heatmap")]
+thread_local! {
+    static BOX_TESTS: std::cell::Cell<u64> = const {std::cell::Cell::new(0)};
+    static PRIMITIVE_TESTS: std::cell::Cell<u64> = const {std::cell::Cell::new(0)};
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TraversalCount {
+    /// Number of bounding boxes tested against a ray
+    pub box_tests: u64,
+    /// Number of spheres and triangles tested against a ray
+    pub primitive_tests: u64,
+}
+
+impl TraversalCount {
+    /// Get the work done by this thread since the last call, always zero without the `heatmap` feature
+    pub fn take() -> TraversalCount {
+        #[cfg(feature = "heatmap")]
+        return TraversalCount {
+            box_tests: BOX_TESTS.with(|x| x.replace(0)),
+            primitive_tests: PRIMITIVE_TESTS.with(|x| x.replace(0)),
+        };
+        #[cfg(not(feature = "heatmap"))]
+        TraversalCount::default()
+    }
+}
+
+#[cfg(feature = "heatmap")]
+fn count_box_tests(n: u64) {
+    BOX_TESTS.with(|x| x.set(x.get() + n));
+}
+
+#[cfg(not(feature = "heatmap"))]
+#[inline(always)]
+fn count_box_tests(_: u64) {}
+
+#[cfg(feature = "heatmap")]
+pub(crate) fn count_primitive_tests(n: u64) {
+    PRIMITIVE_TESTS.with(|x| x.set(x.get() + n));
+}
+
+#[cfg(not(feature = "heatmap"))]
+#[inline(always)]
+pub(crate) fn count_primitive_tests(_: u64) {}
+
+// ------------------------------------------- Tests -------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::randomness::*;
+
+    /// Build the nodes of the bounding boxes, with their order in the leaves
+    fn build(bounding_boxes: &[AABB], strategy: BvhStrategy, num_threads: usize) -> (Vec<BvhNode>, Vec<LeafId>) {
+        let mut content = bounding_boxes.iter().enumerate().map(|(id, aabb)| (id as LeafId, aabb.clone()))
+            .collect::<Vec<_>>();
+        let mut nodes = Vec::new();
+        make_bvh(&mut content, 0, 0, strategy, num_threads, &mut nodes);
+        (nodes, content.iter().map(|(id, _)| *id).collect())
+    }
+
+    fn point_box(x: Real) -> AABB {
+        AABB {min: vector![x, 0.0, 0.0], max: vector![x, 0.0, 0.0]}
+    }
+
+    #[test]
+    fn forced_leaves_fit_in_a_node() {
+        // A cluster of boxes behind far away boxes, that the SAH peels off one level at a time until the maximum
+        // depth, and leaves larger than a count can hold
+        let mut bounding_boxes = vec![point_box(0.0); 70000];
+        bounding_boxes.extend((0..200).map(|i| point_box((2.0 as Real).powi(i))));
+        let strategies = [
+            BvhStrategy::default(),
+            BvhStrategy::Sah {num_bins: 16, max_leaf_size: 100000},
+        ];
+        for strategy in strategies {
+            let (nodes, _) = build(&bounding_boxes, strategy, 1);
+            let mut depths = vec![0; nodes.len()];
+            let mut num_hittables = 0;
+            for (node_id, node) in nodes.iter().enumerate() {
+                assert!(depths[node_id] < MAX_DEPTH);
+                if node.count > 0 {
+                    num_hittables += node.count as usize;
                 } else {
-                    None
+                    depths[node_id + 1] = depths[node_id] + 1;
+                    depths[node.offset as usize] = depths[node_id] + 1;
                 }
-            },
+            }
+            assert_eq!(num_hittables, bounding_boxes.len());
         }
     }
 
-    pub fn hit(&self, ray: &Ray, scene_data: &SceneData) -> Option<(Hit, MaterialId)> {
-        let ray = ray.clone().expand();
-        self.hit_node(&ray, self.root, scene_data)
+    #[test]
+    fn parallel_build_is_deterministic() {
+        let mut rng = Randomizer::from_seed([1; 32]);
+        let bounding_boxes = (0..4 * PARALLEL_THRESHOLD).map(|_| {
+            let center = 10.0 * rng.sample(UnitBall);
+            let size = rng.sample(ClosedRange(0.0, 0.1)) * Rvec3::repeat(1.0);
+            AABB {min: center - size, max: center + size}
+        }).collect::<Vec<_>>();
+        for strategy in [BvhStrategy::Median, BvhStrategy::default()] {
+            let single_thread = build(&bounding_boxes, strategy, 1);
+            for num_threads in [2, 4] {
+                assert_eq!(build(&bounding_boxes, strategy, num_threads), single_thread);
+            }
+        }
     }
-}
\ No newline at end of file
+
+    fn no_triangles(_: TriangleId, _: MeshId) -> (Rvec3, Rvec3, Rvec3) {
+        unreachable!()
+    }
+
+    fn write_spheres(bvh: &Bvh) -> Vec<u8> {
+        let mut writer = cache::Writer::new();
+        bvh.write_cache(&mut writer, |x, writer| match x {
+            Hittable::Sphere {center, radius, ..} => {
+                center.iter().for_each(|x| writer.real(*x));
+                writer.real(*radius);
+            }
+            _ => unreachable!(),
+        });
+        writer.into_bytes()
+    }
+
+    fn read_spheres(bytes: &[u8]) -> Result<Bvh, Box<dyn Error>> {
+        let mut reader = cache::Reader::new(bytes);
+        let bvh = Bvh::read_cache(&mut reader, |reader| {
+            let center = vector![reader.real()?, reader.real()?, reader.real()?];
+            Ok(Hittable::Sphere {center, radius: reader.real()?, material: MaterialId(0)})
+        }, no_triangles)?;
+        if !reader.is_empty() {
+            return Err("Unexpected data after the BVH".into())
+        }
+        Ok(bvh)
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let mut rng = Randomizer::from_seed([2; 32]);
+        let spheres = (0..100).map(|_| {
+            let center = 10.0 * rng.sample(UnitBall);
+            Hittable::Sphere {center, radius: rng.sample(ClosedRange(0.1, 1.0)), material: MaterialId(0)}
+        }).collect::<Vec<_>>();
+        let bounding_boxes = spheres.iter().map(|x| match x {
+            Hittable::Sphere {center, radius, ..} => {
+                AABB {min: center.add_scalar(-radius), max: center.add_scalar(*radius)}
+            }
+            _ => unreachable!(),
+        }).collect();
+        let bvh = Bvh::with_bounding_boxes(spheres, bounding_boxes, BvhStrategy::default(), no_triangles);
+        let bytes = write_spheres(&bvh);
+
+        let read = read_spheres(&bytes).unwrap();
+        assert_eq!(read.nodes, bvh.nodes);
+        assert_eq!(read.strategy, bvh.strategy);
+        assert_eq!(read.build_cost, bvh.build_cost);
+        assert_eq!(write_spheres(&read), bytes);
+
+        // A truncated file
+        for len in [0, 10, bytes.len() / 2, bytes.len() - 1] {
+            assert!(read_spheres(&bytes[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn cache_rejects_invalid_trees() {
+        let leaf = |first| BvhNode::new(&AABB::empty(), first, 1, 0);
+        let branch = |second| BvhNode::new(&AABB::empty(), second, 0, 0);
+        let spheres = || (0..4).map(|_| Hittable::Sphere {center: Rvec3::zeros(), radius: 1.0, material: MaterialId(0)})
+            .collect::<Vec<_>>();
+        let read_nodes = |nodes: Vec<BvhNode>| {
+            let bvh = Bvh {leaves: spheres(), nodes, wide_nodes: Vec::new(), packets: Vec::new(),
+                packet_starts: Vec::new(), strategy: BvhStrategy::Median, build_cost: 0.0};
+            read_spheres(&write_spheres(&bvh))
+        };
+
+        assert!(read_nodes(vec![branch(2), leaf(0), leaf(1)]).is_ok());
+        // A leaf out of the hittables
+        assert!(read_nodes(vec![branch(2), leaf(0), leaf(4)]).is_err());
+        // A child before its parent
+        assert!(read_nodes(vec![branch(2), leaf(0), branch(1)]).is_err());
+        // A node that is the child of two branches
+        assert!(read_nodes(vec![branch(3), branch(3), leaf(0), leaf(1)]).is_err());
+        // Two leaves that share a hittable
+        assert!(read_nodes(vec![branch(2), leaf(0), leaf(0)]).is_err());
+        // A node that is the child of no branch
+        assert!(read_nodes(vec![branch(2), leaf(0), leaf(1), leaf(2)]).is_err());
+    }
+
+    #[test]
+    fn traversal_matches_brute_force() {
+        let model = crate::mesh::obj::load("assets/bunny.obj", &Default::default()).unwrap();
+        let mut scene_data = SceneData {material_table: Vec::new(), texture_table: Vec::new(), mesh_table: model.meshes,
+            instance_table: Vec::new(), light_table: Vec::new()};
+        scene_data.build_blas(BvhStrategy::default());
+        let bvh = scene_data.mesh_table[0].bvh.as_ref().unwrap();
+        let (aabb, center) = (bvh.bounding_box(), bvh.bounding_box().center());
+        let radius = (aabb.max - aabb.min).norm();
+
+        let mut rng = Randomizer::from_seed([3; 32]);
+        let mut num_hits = 0;
+        for i in 0..400 {
+            // Rays from around the mesh and from inside its bounding box, toward a point of the bounding box
+            let origin = if i % 2 == 0 {
+                center + radius * rng.sample(UnitSphere)
+            } else {
+                aabb.min + (aabb.max - aabb.min).component_mul(&vector![rng.gen(), rng.gen(), rng.gen()])
+            };
+            let target = aabb.min + (aabb.max - aabb.min).component_mul(&vector![rng.gen(), rng.gen(), rng.gen()]);
+            let t_max = if i % 3 == 0 {rng.sample(ClosedRange(0.0, 1.0))} else {INFINITY};
+            let ray = Ray {origin, direction: target - origin, t_min: 0.0, t_max};
+
+            let brute_force = bvh.leaves().iter().filter_map(|x| x.hit(&ray, &scene_data).map(|(hit, _)| hit.t))
+                .reduce(Real::min);
+            let closest_with = |lanes| {
+                let mut closest = None;
+                bvh.traverse_with(lanes, &ray, |leaf, packets, ray| {
+                    if let Some((hit, _)) = hit_leaf(leaf, packets, ray, &scene_data) {
+                        ray.t_max = hit.t;
+                        closest = Some(hit.t);
+                    }
+                    false
+                });
+                closest
+            };
+            assert_eq!(bvh.hit(&ray, &scene_data).map(|(hit, _)| hit.t), brute_force, "{:?}", ray);
+            assert_eq!(closest_with(simd::Scalar), brute_force, "{:?}", ray);
+            assert_eq!(bvh.occluded(&ray, &scene_data), brute_force.is_some(), "{:?}", ray);
+            num_hits += brute_force.is_some() as usize;
+        }
+        assert!(num_hits > 100 && num_hits < 380, "{}", num_hits);
+    }
+}
@@ -0,0 +1,248 @@
+/*
+In this file:
+- Binary encoding of the data that is slow to compute, like the meshes and their BVH
+- Cache files that are reused as long as their source file does not change
+*/
+
+use crate::utility::*;
+use std::convert::TryInto;
+use std::error::Error;
+
+/// Increase it whenever the encoding of anything that is cached changes, the older cache files are then ignored
+pub const VERSION: u32 = 5;
+
+const MAGIC: [u8; 4] = *b"RTC2";
+
+// ------------------------------------------- Encoding -------------------------------------------
+
+/// Little-endian encoding, the reals are always written as f64 so that the files do not depend on `Real`
+#[derive(Default)]
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, x: u8) {
+        self.bytes.push(x);
+    }
+
+    pub fn u16(&mut self, x: u16) {
+        self.bytes.extend_from_slice(&x.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, x: u32) {
+        self.bytes.extend_from_slice(&x.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, x: u64) {
+        self.bytes.extend_from_slice(&x.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, x: f32) {
+        self.bytes.extend_from_slice(&x.to_le_bytes());
+    }
+
+    #[allow(clippy::unnecessary_cast)] // Real is not always f64
+    pub fn real(&mut self, x: Real) {
+        self.bytes.extend_from_slice(&(x as f64).to_le_bytes());
+    }
+
+    /// Length of a list, to be read with `Reader::len`
+    pub fn len(&mut self, x: usize) {
+        self.u64(x as u64);
+    }
+
+    pub fn string(&mut self, x: &str) {
+        self.len(x.len());
+        self.bytes.extend_from_slice(x.as_bytes());
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Decode what was written by a `Writer`, in the same order
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader {bytes}
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], Box<dyn Error>> {
+        if self.bytes.len() < N {
+            return Err("The cache file is truncated".into())
+        }
+        let (head, tail) = self.bytes.split_at(N);
+        self.bytes = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Box<dyn Error>> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, Box<dyn Error>> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    pub fn real(&mut self) -> Result<Real, Box<dyn Error>> {
+        Ok(f64::from_le_bytes(self.take()?) as Real)
+    }
+
+    /// Length of a list whose elements take at least `min_size` bytes each. A damaged length fails here rather than
+    /// by allocating too much memory.
+    pub fn len(&mut self, min_size: usize) -> Result<usize, Box<dyn Error>> {
+        let len = self.u64()?;
+        if len.saturating_mul(min_size as u64) > self.bytes.len() as u64 {
+            return Err("The cache file is truncated".into())
+        }
+        Ok(len as usize)
+    }
+
+    pub fn string(&mut self) -> Result<String, Box<dyn Error>> {
+        let len = self.len(1)?;
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(String::from_utf8(head.to_vec())?)
+    }
+
+    /// Whether everything was read
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+// ------------------------------------------- Cache files -------------------------------------------
+
+/// FNV-1a hash of the content of a source file, to know whether its cache is up to date
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, x| (hash ^ *x as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Read a cache file without its header, or None if it is missing, from an older version or from another source
+pub fn read(path: &str, source_hash: u64) -> Option<Vec<u8>> {
+    let mut bytes = std::fs::read(path).ok()?;
+    let mut header = Reader::new(&bytes);
+    let magic = header.take::<4>().ok()?;
+    let version = header.u32().ok()?;
+    let hash = header.u64().ok()?;
+    if magic != MAGIC || version != VERSION || hash != source_hash {
+        return None
+    }
+    let header_size = bytes.len() - header.bytes.len();
+    bytes.drain(..header_size);
+    Some(bytes)
+}
+
+/// Write a cache file of the data that was made from the source with the given hash
+pub fn write(path: &str, source_hash: u64, content: Writer) -> Result<(), Box<dyn Error>> {
+    let mut bytes = Vec::with_capacity(16 + content.bytes.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&source_hash.to_le_bytes());
+    bytes.extend_from_slice(&content.bytes);
+    // Write then rename, so that another process never reads half a file
+    let temporary_path = format!("{}.tmp", path);
+    std::fs::write(&temporary_path, bytes)?;
+    std::fs::rename(&temporary_path, path)?;
+    Ok(())
+}
+
+// ------------------------------------------- Tests -------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_everything() -> Writer {
+        let mut writer = Writer::new();
+        writer.u8(1);
+        writer.u16(0x0203);
+        writer.u32(0x04050607);
+        writer.u64(0x08090a0b0c0d0e0f);
+        writer.f32(-1.5);
+        writer.real(PI);
+        writer.len(3);
+        writer.string("name #1");
+        writer
+    }
+
+    fn read_everything(reader: &mut Reader) -> Result<(), Box<dyn Error>> {
+        assert_eq!(reader.u8()?, 1);
+        assert_eq!(reader.u16()?, 0x0203);
+        assert_eq!(reader.u32()?, 0x04050607);
+        assert_eq!(reader.u64()?, 0x08090a0b0c0d0e0f);
+        assert_eq!(reader.f32()?, -1.5);
+        assert_eq!(reader.real()?, PI);
+        assert_eq!(reader.len(0)?, 3);
+        assert_eq!(reader.string()?, "name #1");
+        Ok(())
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = write_everything().into_bytes();
+        let mut reader = Reader::new(&bytes);
+        read_everything(&mut reader).unwrap();
+        assert!(reader.is_empty());
+
+        // Every truncation fails instead of reading garbage
+        for len in 0..bytes.len() {
+            assert!(read_everything(&mut Reader::new(&bytes[..len])).is_err());
+        }
+        // A length larger than what is left
+        let mut writer = Writer::new();
+        writer.len(1000);
+        writer.u32(0);
+        assert!(Reader::new(&writer.into_bytes()).len(1).is_err());
+    }
+
+    #[test]
+    fn cache_files() {
+        let path = std::env::temp_dir().join(format!("raytracing2_cache_test_{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let expected = write_everything().into_bytes();
+        write(path, 42, write_everything()).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+
+        assert_eq!(read(path, 42), Some(expected));
+        // Another source
+        assert_eq!(read(path, 43), None);
+        // Another version
+        let mut other_version = bytes.clone();
+        other_version[4..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        std::fs::write(path, other_version).unwrap();
+        assert_eq!(read(path, 42), None);
+        // Not a cache file
+        let mut other_magic = bytes.clone();
+        other_magic[0] = b'X';
+        std::fs::write(path, other_magic).unwrap();
+        assert_eq!(read(path, 42), None);
+        // A truncated header
+        std::fs::write(path, &bytes[..10]).unwrap();
+        assert_eq!(read(path, 42), None);
+
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(read(path, 42), None);
+    }
+}
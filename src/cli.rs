@@ -0,0 +1,162 @@
+use raytracing2::render::Aov;
+use crate::example_scenes;
+
+// ------------------------------------------- Options -------------------------------------------
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub scene: String,
+    pub width: u32,
+    pub height: u32,
+    pub num_samples: u32,
+    pub max_bounce: usize,
+    pub min_bounce: usize,
+    pub tile_size: u32,
+    pub num_workers: usize,
+    /// The extension chooses the format: tga or pfm
+    pub output: String,
+    pub transparent_background: bool,
+    pub aovs: Vec<Aov>,
+    pub seed: Option<u64>,
+    /// Render the cost of hitting the camera rays instead of the scene
+    pub heatmap: bool,
+    pub bvh_stats: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            scene: "bunny".to_string(),
+            width: 800,
+            height: 600,
+            num_samples: 4,
+            max_bounce: 64,
+            min_bounce: 3,
+            tile_size: 32,
+            num_workers: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            output: "output.tga".to_string(),
+            transparent_background: false,
+            aovs: Vec::new(),
+            seed: None,
+            heatmap: false,
+            bvh_stats: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// 8 bits per channel with gamma correction
+    Tga,
+    /// 32 bits float per channel, linear
+    Pfm,
+}
+
+impl Options {
+    pub fn output_format(&self) -> Result<OutputFormat, String> {
+        let extension = self.output.rsplit('.').next().unwrap_or_default().to_ascii_lowercase();
+        match extension.as_str() {
+            "tga" => Ok(OutputFormat::Tga),
+            "pfm" => Ok(OutputFormat::Pfm),
+            _ => Err(format!("Unsupported output format \"{}\", use .tga or .pfm", self.output)),
+        }
+    }
+
+    /// The output path without its extension, to name the AOV files
+    pub fn output_prefix(&self) -> &str {
+        self.output.rsplit_once('.').map_or(&self.output, |(prefix, _)| prefix)
+    }
+}
+
+// ------------------------------------------- Parsing -------------------------------------------
+
+pub enum Command {
+    Render(Options),
+    Help,
+}
+
+/// Parse the command line arguments, without the name of the program
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut options = Options::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("Missing value after {}", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-s" | "--scene" => options.scene = value()?,
+            "-W" | "--width" => options.width = parse_number(&arg, &value()?)?,
+            "-H" | "--height" => options.height = parse_number(&arg, &value()?)?,
+            "-n" | "--samples" => options.num_samples = parse_number(&arg, &value()?)?,
+            "--max-bounce" => options.max_bounce = parse_number(&arg, &value()?)?,
+            "--min-bounce" => options.min_bounce = parse_number(&arg, &value()?)?,
+            "--tile-size" => options.tile_size = parse_number(&arg, &value()?)?,
+            "-j" | "--workers" => options.num_workers = parse_number(&arg, &value()?)?,
+            "-o" | "--output" => options.output = value()?,
+            "--transparent" => options.transparent_background = true,
+            "--aovs" => options.aovs = parse_aovs(&value()?)?,
+            "--seed" => options.seed = Some(parse_number(&arg, &value()?)?),
+            "--heatmap" if !cfg!(feature = "heatmap") => {
+                return Err("The heatmap needs the program to be built with --features heatmap".into())
+            }
+            "--heatmap" => options.heatmap = true,
+            "--bvh-stats" => options.bvh_stats = true,
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+
+    // Check the values that would make the renderer panic
+    if options.width == 0 || options.height == 0 {
+        return Err("The resolution must not be zero".into())
+    }
+    if options.num_samples == 0 || options.max_bounce == 0 || options.tile_size == 0 || options.num_workers == 0 {
+        return Err("The number of samples, of bounces, of workers and the tile size must not be zero".into())
+    }
+    options.output_format()?;
+    Ok(Command::Render(options))
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value for {}: {}", arg, value))
+}
+
+fn parse_aovs(value: &str) -> Result<Vec<Aov>, String> {
+    if value == "all" {
+        return Ok(Aov::ALL.to_vec())
+    }
+    value.split(',').map(|name| Aov::from_name(name).ok_or(format!("Unknown AOV {}", name))).collect()
+}
+
+// ------------------------------------------- Help -------------------------------------------
+
+pub fn print_help() {
+    let defaults = Options::default();
+    println!("Ray tracing on CPU");
+    println!();
+    println!("USAGE: raytracing2 [OPTIONS]");
+    println!();
+    println!("OPTIONS:");
+    println!("  -s, --scene <NAME|PATH>  Example scene or scene file to render [default: {}]", defaults.scene);
+    println!("  -W, --width <PIXELS>     Width of the image [default: {}]", defaults.width);
+    println!("  -H, --height <PIXELS>    Height of the image [default: {}]", defaults.height);
+    println!("  -n, --samples <N>        Samples per pixel [default: {}]", defaults.num_samples);
+    println!("      --max-bounce <N>     Maximum number of rays per path [default: {}]", defaults.max_bounce);
+    println!("      --min-bounce <N>     Rays per path before russian roulette [default: {}]", defaults.min_bounce);
+    println!("      --tile-size <PIXELS> Size of the tiles given to the workers [default: {}]", defaults.tile_size);
+    println!("  -j, --workers <N>        Number of rendering threads [default: {}]", defaults.num_workers);
+    println!("  -o, --output <PATH>      Output image, .tga or .pfm [default: {}]", defaults.output);
+    println!("      --transparent        Make the background transparent (tga only)");
+    println!("      --aovs <LIST>        Comma-separated AOVs to save next to the output, or \"all\"");
+    println!("      --seed <N>           Seed of the random numbers, for reproducible renders");
+    println!("      --heatmap            Color the pixels by the number of box and primitive tests of the camera rays");
+    println!("                           (needs the program to be built with --features heatmap)");
+    println!("      --bvh-stats          Print statistics about the quality of the BVHs");
+    println!("  -h, --help               Print this help");
+    println!();
+    println!("SCENES:");
+    for (name, _) in example_scenes::SCENES {
+        println!("  {}", name);
+    }
+    println!();
+    println!("AOVS:");
+    println!("  {}", Aov::ALL.iter().map(|aov| aov.name()).collect::<Vec<_>>().join(", "));
+}
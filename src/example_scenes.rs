@@ -8,18 +8,23 @@ use raytracing2::randomness::*;
 use raytracing2::image::*;
 use raytracing2::mesh::*;
 
-// TODO: Have a scene verifier that detects missing texture/material and circular references?
-// It would use string ids instead of integers for ease of use and to allow the merging or multiple scenes
-
-pub struct ExampleScene {
-    pub camera: Camera,
-    pub scene_data: SceneData,
-    pub root: Hittable,
-    pub background: Emit,
-}
-
-#[allow(dead_code)]
-pub fn three_balls() -> ExampleScene {
+pub type MakeScene = fn() -> Scene;
+
+/// The scenes that can be picked by name
+pub const SCENES: &[(&str, MakeScene)] = &[
+    ("three_balls", three_balls),
+    ("more_balls", more_balls),
+    ("more_balls_optimized", more_balls_optimized),
+    ("two_balls", two_balls),
+    ("earth", earth),
+    ("one_triangle", one_triangle),
+    ("glass_bunny", glass_bunny),
+    ("bunny", bunny),
+    ("small_lamp", small_lamp),
+    ("bunny_crowd", bunny_crowd),
+];
+
+pub fn three_balls() -> Scene {
     let camera = Camera {
         aspect_ratio: 1.0,
         fov: FRAC_PI_2,
@@ -54,13 +59,14 @@ pub fn three_balls() -> ExampleScene {
         Hittable::Sphere {center: vector![1.0, 0.0, -1.0], radius: 0.5, material: MaterialId(3)}, // Glass sphere
     ]);
 
-    let scene_data = SceneData {material_table, texture_table, mesh_table: Vec::new()};
+    let scene_data = SceneData {
+        material_table, texture_table, mesh_table: Vec::new(), instance_table: Vec::new(), light_table: Vec::new()
+    };
     let background = Emit::SkyGradient;
-    ExampleScene {camera, scene_data, root, background}
+    Scene {camera, scene_data, root, background}
 }
 
-#[allow(dead_code)]
-pub fn more_balls() -> ExampleScene {
+pub fn more_balls() -> Scene {
     let camera = Camera {
         aspect_ratio: 1.0,
         fov: FRAC_PI_2,
@@ -132,25 +138,25 @@ pub fn more_balls() -> ExampleScene {
         }
     }
 
-    let scene_data = SceneData {material_table, texture_table, mesh_table: Vec::new()};
+    let scene_data = SceneData {
+        material_table, texture_table, mesh_table: Vec::new(), instance_table: Vec::new(), light_table: Vec::new()
+    };
     let background = Emit::SkyGradient;
-    ExampleScene {camera, scene_data, root: Hittable::List(root), background}
+    Scene {camera, scene_data, root: Hittable::List(root), background}
 }
 
-#[allow(dead_code)]
-pub fn more_balls_optimized() -> ExampleScene {
+pub fn more_balls_optimized() -> Scene {
     let mut example_scene = more_balls();
     let list = if let Hittable::List(list) = example_scene.root {
         list
     } else {
         unreachable!()
     };
-    example_scene.root = Hittable::Bvh(Bvh::new(list, &example_scene.scene_data));
+    example_scene.root = Hittable::Bvh(Bvh::new(list, &example_scene.scene_data, BvhStrategy::default()));
     example_scene
 }
 
-#[allow(dead_code)]
-pub fn two_balls() -> ExampleScene {
+pub fn two_balls() -> Scene {
     let camera = Camera {
         aspect_ratio: 1.0,
         fov: FRAC_PI_2,
@@ -175,19 +181,20 @@ pub fn two_balls() -> ExampleScene {
         Material::new(Scatter::Lambert, Absorb::AlbedoMap(TextureId(3)), Emit::None),
     ];
 
-    let scene_data = SceneData {material_table, texture_table, mesh_table: Vec::new()};
+    let scene_data = SceneData {
+        material_table, texture_table, mesh_table: Vec::new(), instance_table: Vec::new(), light_table: Vec::new()
+    };
 
     let root = Hittable::Bvh(Bvh::new(vec![
         Hittable::Sphere {center: vector![0.0, -10.0, 0.0], radius: 10.0, material: MaterialId(0)},
         Hittable::Sphere {center: vector![0.0, 10.0, 0.0], radius: 10.0, material: MaterialId(1)},
-    ], &scene_data));
+    ], &scene_data, BvhStrategy::default()));
 
     let background = Emit::SkyGradient;
-    ExampleScene {camera, scene_data, root, background}
+    Scene {camera, scene_data, root, background}
 }
 
-#[allow(dead_code)]
-pub fn earth() -> ExampleScene {
+pub fn earth() -> Scene {
     let camera = Camera {
         aspect_ratio: 1.0,
         fov: PI / 9.0,
@@ -208,19 +215,21 @@ pub fn earth() -> ExampleScene {
         Material::new(Scatter::Lambert, Absorb::AlbedoMap(TextureId(0)), Emit::None)
     ];
 
-    let scene_data = SceneData {material_table, texture_table, mesh_table: Vec::new()};
+    let scene_data = SceneData {
+        material_table, texture_table, mesh_table: Vec::new(), instance_table: Vec::new(), light_table: Vec::new()
+    };
     
     let root = Hittable::Bvh(Bvh::new(vec![
         Hittable::Sphere {center: vector![0.0, 0.0, 0.0], radius: 2.0, material: MaterialId(0)}
-    ], &scene_data));
+    ], &scene_data, BvhStrategy::default()));
 
     let background = Emit::SkyGradient;
-    ExampleScene {camera, root, scene_data, background}
+    Scene {camera, root, scene_data, background}
 }
 
-#[allow(dead_code)]
-pub fn one_triangle() -> ExampleScene {
+pub fn one_triangle() -> Scene {
     let normal = vector![1.0, 1.0, 1.0].normalize();
+    let tangent = vector![0.0, 0.0, 0.0];
     let uv = vector![0.0, 0.0];
 
     let material_table = vec![
@@ -229,22 +238,24 @@ pub fn one_triangle() -> ExampleScene {
     ];
 
     let mesh_table = vec![
-        Mesh {
-            vertices: vec![
-                Vertex {position: vector![1.0, 0.0, 0.0], normal, uv},
-                Vertex {position: vector![0.0, 1.0, 0.0], normal, uv},
-                Vertex {position: vector![0.0, 0.0, 1.0], normal, uv},
+        Mesh::new(
+            vec![
+                Vertex {position: vector![1.0, 0.0, 0.0], normal, tangent, bitangent_sign: 1.0, uv},
+                Vertex {position: vector![0.0, 1.0, 0.0], normal, tangent, bitangent_sign: 1.0, uv},
+                Vertex {position: vector![0.0, 0.0, 1.0], normal, tangent, bitangent_sign: 1.0, uv},
             ],
-            indices: vec![0, 1, 2],
-            material: MaterialId(0)
-        }
+            vec![0, 1, 2],
+            MaterialId(0)
+        )
     ];
 
-    let scene_data = SceneData {material_table, mesh_table, texture_table: Vec::new()};
+    let scene_data = SceneData {
+        material_table, mesh_table, texture_table: Vec::new(), instance_table: Vec::new(), light_table: Vec::new()
+    };
     let root = Hittable::Bvh(Bvh::new(vec![
         Hittable::Triangle {triangle: TriangleId(0), mesh: MeshId(0)}, // One lone triangle
         Hittable::Sphere {center: vector![0.0, -1000.0, -1.0], radius: 1000.0, material: MaterialId(1)}, // Ground
-    ], &scene_data));
+    ], &scene_data, BvhStrategy::default()));
     let background = Emit::SkyGradient;
     let camera = Camera {
         aspect_ratio: 1.0,
@@ -258,12 +269,12 @@ pub fn one_triangle() -> ExampleScene {
         ),
     };
 
-    ExampleScene {root, camera, scene_data, background}
+    Scene {root, camera, scene_data, background}
 }
 
-#[allow(dead_code)]
-pub fn glass_bunny() -> ExampleScene {
-    let bunny = obj::load("assets/bunny_flat.obj").unwrap();
+pub fn glass_bunny() -> Scene {
+    let bunny = obj::load_cached("assets/bunny_flat.obj", MeshId(0), BvhStrategy::default(), &obj::Options::default())
+        .unwrap().meshes.remove(0);
     let mut hittable_list = Vec::new();
 
     let material_table = vec![
@@ -275,9 +286,7 @@ pub fn glass_bunny() -> ExampleScene {
         Texture::Image(tga::load("assets/sky_panorama.tga").unwrap())
     ];
 
-    hittable_list.extend(
-        bunny.iter_triangles().map(|tid| Hittable::Triangle {triangle: tid, mesh: MeshId(0)})
-    );
+    hittable_list.push(Hittable::Instance(InstanceId(0)));
     hittable_list.push(
         Hittable::Sphere {center: vector![0.0, -1000.0, -1.0], radius: 1000.0, material: MaterialId(1)}
     );
@@ -286,9 +295,13 @@ pub fn glass_bunny() -> ExampleScene {
         bunny
     ];
 
-    let scene_data = SceneData {material_table, mesh_table, texture_table};
-    let root = Hittable::Bvh(Bvh::new(hittable_list, &scene_data));
-    // let root = Hittable::List(hittable_list); // OOH THAT'S SLOW
+    let instance_table = vec![
+        Instance::new(MeshId(0), Transformation::identity())
+    ];
+
+    let mut scene_data = SceneData {material_table, mesh_table, texture_table, instance_table, light_table: Vec::new()};
+    scene_data.build_blas(BvhStrategy::default());
+    let root = Hittable::Bvh(Bvh::new(hittable_list, &scene_data, BvhStrategy::default()));
     let background = Emit::SkySphere(TextureId(0));
     let camera = Camera {
         aspect_ratio: 1.0,
@@ -302,12 +315,12 @@ pub fn glass_bunny() -> ExampleScene {
         ),
     };
 
-    ExampleScene {root, camera, scene_data, background}
+    Scene {root, camera, scene_data, background}
 }
 
-#[allow(dead_code)]
-pub fn bunny() -> ExampleScene {
-    let bunny = obj::load("assets/bunny.obj").unwrap();
+pub fn bunny() -> Scene {
+    let bunny = obj::load_cached("assets/bunny.obj", MeshId(0), BvhStrategy::default(), &obj::Options::default())
+        .unwrap().meshes.remove(0);
     let mut hittable_list = Vec::new();
 
     let material_table = vec![
@@ -319,9 +332,7 @@ pub fn bunny() -> ExampleScene {
         Texture::Image(tga::load("assets/sky_panorama.tga").unwrap())
     ];
 
-    hittable_list.extend(
-        bunny.iter_triangles().map(|tid| Hittable::Triangle {triangle: tid, mesh: MeshId(0)})
-    );
+    hittable_list.push(Hittable::Instance(InstanceId(0)));
     hittable_list.push(
         Hittable::Sphere {center: vector![0.0, -1000.0, -1.0], radius: 1000.0, material: MaterialId(1)}
     );
@@ -330,9 +341,13 @@ pub fn bunny() -> ExampleScene {
         bunny
     ];
 
-    let scene_data = SceneData {material_table, mesh_table, texture_table};
-    let root = Hittable::Bvh(Bvh::new(hittable_list, &scene_data));
-    // let root = Hittable::List(hittable_list); // OOH THAT'S SLOW
+    let instance_table = vec![
+        Instance::new(MeshId(0), Transformation::identity())
+    ];
+
+    let mut scene_data = SceneData {material_table, mesh_table, texture_table, instance_table, light_table: Vec::new()};
+    scene_data.build_blas(BvhStrategy::default());
+    let root = Hittable::Bvh(Bvh::new(hittable_list, &scene_data, BvhStrategy::default()));
     let background = Emit::SkySphere(TextureId(0));
     let camera = Camera {
         aspect_ratio: 1.0,
@@ -346,5 +361,107 @@ pub fn bunny() -> ExampleScene {
         ),
     };
 
-    ExampleScene {root, camera, scene_data, background}
-}
\ No newline at end of file
+    Scene {root, camera, scene_data, background}
+}
+
+pub fn small_lamp() -> Scene {
+    let camera = Camera {
+        aspect_ratio: 1.0,
+        fov: FRAC_PI_3,
+        focal_dist: 1.0,
+        lens_radius: 0.0,
+        transformation: Transformation::lookat(
+            &vector![0.0, 1.0, 4.0],
+            &vector![0.0, 0.5, 0.0],
+            &vector![0.0, 1.0, 0.0]
+        ),
+    };
+
+    let texture_table = vec![
+        Texture::Solid(rgb(0.2, 0.2, 0.2)),
+        Texture::Solid(rgb(0.8, 0.8, 0.8)),
+        Texture::Checker {odd: TextureId(0), even: TextureId(1)},
+    ];
+
+    let material_table = vec![
+        Material::new(Scatter::Lambert, Absorb::AlbedoMap(TextureId(2)), Emit::None),
+        Material::new(Scatter::Lambert, Absorb::Albedo(rgb(0.7, 0.2, 0.1)), Emit::None),
+        Material::new(Scatter::Metal {fuzziness: 0.3}, Absorb::Albedo(rgb(0.8, 0.8, 0.8)), Emit::None),
+        Material::new(Scatter::None, Absorb::BlackBody, Emit::Color(rgb(50.0, 45.0, 40.0))),
+    ];
+
+    let scene_data = SceneData {
+        material_table, texture_table, mesh_table: Vec::new(), instance_table: Vec::new(), light_table: Vec::new()
+    };
+
+    let root = Hittable::Bvh(Bvh::new(vec![
+        Hittable::Sphere {center: vector![0.0, -1000.0, 0.0], radius: 1000.0, material: MaterialId(0)}, // Floor
+        Hittable::Sphere {center: vector![-0.6, 0.5, 0.0], radius: 0.5, material: MaterialId(1)}, // Diffuse sphere
+        Hittable::Sphere {center: vector![0.6, 0.5, 0.0], radius: 0.5, material: MaterialId(2)}, // Metal sphere
+        Hittable::Sphere {center: vector![0.0, 1.6, 0.5], radius: 0.05, material: MaterialId(3)}, // Lamp
+    ], &scene_data, BvhStrategy::default()));
+
+    let background = Emit::None;
+    Scene {camera, scene_data, root, background}
+}
+
+pub fn bunny_crowd() -> Scene {
+    let bunny = obj::load_cached("assets/bunny.obj", MeshId(0), BvhStrategy::default(), &obj::Options::default())
+        .unwrap().meshes.remove(0);
+
+    let texture_table = vec![
+        Texture::Image(tga::load("assets/sky_panorama.tga").unwrap())
+    ];
+
+    let mut material_table = vec![
+        Material::new(Scatter::Lambert, Absorb::Albedo(rgb(0.5, 0.5, 0.5)), Emit::None), // Ground
+    ];
+
+    // Put 400 copies of the bunny on a grid, each with its own orientation, size and material
+    let mut rng = Randomizer::from_seed([249; 32]);
+    let mut instance_table = Vec::new();
+    for x in -10..10 {
+        for z in -10..10 {
+            let material = MaterialId(material_table.len() as _);
+            let albedo = rgb(rng.gen::<Real>(), rng.gen::<Real>(), rng.gen::<Real>());
+            let scatter = if rng.sample(Bernoulli(0.5)) {
+                Scatter::Lambert
+            } else {
+                Scatter::Metal {fuzziness: rng.sample(ClosedRange(0.0, 0.2))}
+            };
+            material_table.push(Material::new(scatter, Absorb::Albedo(albedo), Emit::None));
+
+            let transformation = Transformation::scaling(rng.sample(ClosedRange(0.2, 0.4)))
+                .then(&Transformation::rotation(&vector![0.0, 1.0, 0.0], rng.sample(ClosedRange(0.0, TAU))))
+                .then(&Transformation::translation(&vector![x as Real + 0.5, 0.0, z as Real + 0.5]));
+            instance_table.push(Instance::new(MeshId(0), transformation).with_material(material));
+        }
+    }
+
+    let mut hittable_list = (0..instance_table.len())
+        .map(|i| Hittable::Instance(InstanceId(i as _)))
+        .collect::<Vec<_>>();
+    hittable_list.push(
+        Hittable::Sphere {center: vector![0.0, -1000.0, 0.0], radius: 1000.0, material: MaterialId(0)}
+    );
+
+    let mut scene_data = SceneData {
+        material_table, texture_table, mesh_table: vec![bunny], instance_table, light_table: Vec::new()
+    };
+    scene_data.build_blas(BvhStrategy::default());
+    let root = Hittable::Bvh(Bvh::new(hittable_list, &scene_data, BvhStrategy::default()));
+    let background = Emit::SkySphere(TextureId(0));
+    let camera = Camera {
+        aspect_ratio: 1.0,
+        fov: FRAC_PI_4,
+        focal_dist: 1.0,
+        lens_radius: 0.0,
+        transformation: Transformation::lookat(
+            &vector![-6.0, 4.0, 8.0],
+            &vector![0.0, 0.0, 0.0],
+            &vector![0.0, 1.0, 0.0]
+        ),
+    };
+
+    Scene {root, camera, scene_data, background}
+}
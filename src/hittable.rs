@@ -3,13 +3,42 @@ use crate::utility::*;
 use crate::bvh::*;
 use crate::mesh::*;
 use crate::material::MaterialId;
+use crate::randomness::noise;
+use crate::simd::{self, Lanes, Triangles4, TriangleHits4};
 
 // ------------------------------------------- Hittable -------------------------------------------
 
+/// The primitive that was hit by a ray
+#[derive(Debug, Clone, Copy)]
+pub enum Primitive {
+    None,
+    Sphere {center: Rvec3, radius: Real},
+    /// The instance is none for the triangles that are directly in the scene
+    Triangle {triangle: TriangleId, mesh: MeshId, instance: Option<InstanceId>},
+}
+
+impl Primitive {
+    /// A stable identifier to make masks when compositing. It is a hash that fits in 24 bits, so it is exact in a f32.
+    pub fn id(&self) -> Option<u32> {
+        let hash = match self {
+            Self::None => return None,
+            Self::Sphere {center, radius} => noise::integer(
+                center.x.to_bits() as isize, center.y.to_bits() as isize, center.z.to_bits() as isize,
+                radius.to_bits() as isize
+            ),
+            Self::Triangle {triangle, mesh, instance} => noise::integer(
+                triangle.0 as isize, mesh.0 as isize, instance.map_or(-1, |x| x.0 as isize), -1
+            ),
+        };
+        Some(hash as u32 & 0xffffff)
+    }
+}
+
 #[derive(Clone)]
 pub enum Hittable {
     Sphere {center: Rvec3, radius: Real, material: MaterialId},
     Triangle {triangle: TriangleId, mesh: MeshId},
+    Instance(InstanceId),
     List(Vec<Hittable>),
     Bvh(Bvh),
 }
@@ -19,15 +48,29 @@ impl Hittable {
         match self {
             Self::Sphere {center, radius, material} => hit_sphere(center, *radius, *material, ray),
             Self::Triangle {triangle, mesh} => hit_triangle(*triangle, *mesh, ray, scene_data),
+            Self::Instance(instance) => hit_instance(*instance, ray, scene_data),
             Self::List(list) => hit_list(list, ray, scene_data),
             Self::Bvh(bvh) => bvh.hit(ray, scene_data),
         }
     }
 
+    /// Whether the ray hits anything, for shadow rays. It is faster than `hit` because any hit will do and there is
+    /// nothing to shade.
+    pub fn occluded(&self, ray: &Ray, scene_data: &SceneData) -> bool {
+        match self {
+            Self::Sphere {center, radius, ..} => intersect_sphere(center, *radius, ray).is_some(),
+            Self::Triangle {triangle, mesh} => intersect_triangle(*triangle, *mesh, ray, scene_data).is_some(),
+            Self::Instance(instance) => occluded_instance(*instance, ray, scene_data),
+            Self::List(list) => list.iter().any(|x| x.occluded(ray, scene_data)),
+            Self::Bvh(bvh) => bvh.occluded(ray, scene_data),
+        }
+    }
+
     pub fn bounding_box(&self, scene_data: &SceneData) -> AABB {
         match self {
             Self::Sphere {center, radius, ..} => bounding_box_sphere(center, *radius),
             Self::Triangle {triangle, mesh} => bounding_box_triangle(*triangle, *mesh, scene_data),
+            Self::Instance(instance) => bounding_box_instance(*instance, scene_data),
             Self::List(list) => bounding_box_list(list, scene_data),
             Self::Bvh(_) => panic!("Do not take the bounding box of a Bvh. What are you trying to do?")
         }
@@ -37,6 +80,41 @@ impl Hittable {
 // ------------------------------------------- Hit implementations -------------------------------------------
 
 fn hit_sphere(center: &Rvec3, radius: Real, material: MaterialId, ray: &Ray) -> Option<(Hit, MaterialId)> {
+    let t = intersect_sphere(center, radius, ray)?;
+    let (position, position_error) = sphere_point(center, radius, &ray.at(t));
+    let normal = (position - center).normalize();
+    let front_face = ray.direction.dot(&normal) < 0.0;
+    let (tangent, bitangent) = sphere_tangents(&normal);
+    let uv = vector![0.5 - normal.z.atan2(normal.x) / TAU, normal.y.asin() / PI + 0.5];
+    let primitive = Primitive::Sphere {center: *center, radius};
+    let hit = Hit {t, position, position_error, normal, geometric_normal: normal, front_face, tangent, bitangent, uv,
+        primitive};
+    Some((hit, material))
+}
+
+/// The tangent and bitangent of a sphere with the given normal, they follow its equirectangular texture coordinates
+pub(crate) fn sphere_tangents(normal: &Rvec3) -> (Rvec3, Rvec3) {
+    let tangent = vector![normal.z, 0.0, -normal.x];
+    let length = tangent.norm();
+    if length < SMOL {
+        // At the poles
+        return orthonormal_basis(normal)
+    }
+    let tangent = tangent / length;
+    (tangent, normal.cross(&tangent))
+}
+
+/// Project a point that is approximately on the sphere onto it, which is more precise than the point along the ray.
+/// Also returns the bound of the rounding errors of the projection.
+pub(crate) fn sphere_point(center: &Rvec3, radius: Real, approx: &Rvec3) -> (Rvec3, Rvec3) {
+    let local = approx - center;
+    let local = local * (radius / local.norm());
+    (center + local, gamma(6) * (local.abs() + center.abs()))
+}
+
+/// The distance along the ray to the sphere
+fn intersect_sphere(center: &Rvec3, radius: Real, ray: &Ray) -> Option<Real> {
+    count_primitive_tests(1);
     let to_center = ray.origin - center;
     let a = ray.direction.norm_squared();
     let half_b = ray.direction.dot(&to_center);
@@ -55,56 +133,257 @@ fn hit_sphere(center: &Rvec3, radius: Real, material: MaterialId, ray: &Ray) ->
             return None
         }
     }
-
-    let position = ray.at(t);
-    let normal = (position - center).normalize();
-    let uv = vector![0.5 - normal.z.atan2(normal.x) / TAU, normal.y.asin() / PI + 0.5];
-    Some((Hit {t, position, normal, uv}, material))
+    Some(t)
 }
 
 fn hit_triangle(triangle: TriangleId, mesh: MeshId, ray: &Ray, scene_data: &SceneData) -> Option<(Hit, MaterialId)> {
-    // https://facultyweb.cs.wwu.edu/~wehrwes/courses/csci480_20w/lectures/L10/L10.pdf
+    let (t, u, v) = intersect_triangle(triangle, mesh, ray, scene_data)?;
+    Some(shade_triangle(triangle, mesh, ray, t, u, v, scene_data))
+}
+
+/// Interpolate the normals and texture coordinates at a hit of the triangle
+fn shade_triangle(triangle: TriangleId, mesh: MeshId, ray: &Ray, t: Real, u: Real, v: Real, scene_data: &SceneData)
+    -> (Hit, MaterialId)
+{
+    let primitive = Primitive::Triangle {triangle, mesh, instance: None};
     let triangle = scene_data.mesh_table[mesh.to_index()].get_triangle(triangle);
-    let a = triangle.0.position;
-    let b = triangle.1.position;
-    let c = triangle.2.position;
-    let ba = a - b;
-    let ca = a - c;
-    let pa = a - ray.origin;
-    let d = ray.direction;
-
-    // Solve this system of equations: [ a-b  a-c  d ] * [ u  v  t ]^T = a-p
-    let det = ba.x * ca.y * d.z + ba.y * ca.z * d.x + ba.z * ca.x * d.y
-            - ba.x * ca.z * d.y - ba.y * ca.x * d.z - ba.z * ca.y * d.x;
-
-    if det.abs() < SMOL {
-        return None
+    let w = 1.0 - u - v;
+    let (a, b, c) = (&triangle.0.position, &triangle.1.position, &triangle.2.position);
+    let (position, position_error) = triangle_point(a, b, c, u, v);
+    let (normal, geometric_normal) = triangle_normals(&triangle, u, v);
+    let front_face = ray.direction.dot(&geometric_normal) < 0.0;
+    let (tangent, bitangent) = triangle_tangents(&triangle, u, v, &normal);
+    let uv = w * triangle.0.uv + u * triangle.1.uv + v * triangle.2.uv;
+    let hit = Hit {t, position, position_error, normal, geometric_normal, front_face, tangent, bitangent, uv,
+        primitive};
+    (hit, scene_data.mesh_table[mesh.to_index()].material)
+}
+
+/// The shading and geometric normals of a triangle. The shading normal is the face normal where the vertex normals
+/// are missing or cancel out, and the geometric normal is flipped to its side because the vertex normals are more
+/// reliable than the winding of the triangles to tell the outside.
+fn triangle_normals(triangle: &(Vertex, Vertex, Vertex), u: Real, v: Real) -> (Rvec3, Rvec3) {
+    let (a, b, c) = (&triangle.0.position, &triangle.1.position, &triangle.2.position);
+    let geometric_normal = (b - a).cross(&(c - a)).normalize();
+    let normal = (1.0 - u - v) * triangle.0.normal + u * triangle.1.normal + v * triangle.2.normal;
+    let length = normal.norm();
+    if length < SMOL || !length.is_finite() {
+        return (geometric_normal, geometric_normal)
     }
-    let inv_det = 1.0 / det;
+    let normal = normal / length;
+    (normal, if normal.dot(&geometric_normal) < 0.0 {-geometric_normal} else {geometric_normal})
+}
 
-    let t = (pa.x * (ba.y * ca.z - ba.z * ca.y)
-           + pa.y * (ba.z * ca.x - ba.x * ca.z)
-           + pa.z * (ba.x * ca.y - ba.y * ca.x)) * inv_det;
-    
-    let u = (pa.x * (ca.y * d.z - ca.z * d.y)
-           + pa.y * (ca.z * d.x - ca.x * d.z)
-           + pa.z * (ca.x * d.y - ca.y * d.x)) * inv_det;
+/// Interpolate the tangents of a triangle and complete them into a frame with the shading normal
+fn triangle_tangents(triangle: &(Vertex, Vertex, Vertex), u: Real, v: Real, normal: &Rvec3) -> (Rvec3, Rvec3) {
+    let tangent = (1.0 - u - v) * triangle.0.tangent + u * triangle.1.tangent + v * triangle.2.tangent;
+    let tangent = tangent - normal * normal.dot(&tangent);
+    let length = tangent.norm();
+    if length < SMOL {
+        return orthonormal_basis(normal)
+    }
+    let tangent = tangent / length;
 
-    let v = (pa.x * (ba.z * d.y - ba.y * d.z)
-           + pa.y * (ba.x * d.z - ba.z * d.x)
-           + pa.z * (ba.y * d.x - ba.x * d.y)) * inv_det;
-    
+    // The bitangent follows increasing v, which is on the other side when the texture is mirrored
+    let sign = (1.0 - u - v) * triangle.0.bitangent_sign + u * triangle.1.bitangent_sign
+        + v * triangle.2.bitangent_sign;
+    let bitangent = normal.cross(&tangent);
+    (tangent, if sign < 0.0 {-bitangent} else {bitangent})
+}
+
+/// The point of a triangle at the given barycentric coordinates, which is more precise than the point along the ray,
+/// and the bound of its rounding errors
+pub(crate) fn triangle_point(a: &Rvec3, b: &Rvec3, c: &Rvec3, u: Real, v: Real) -> (Rvec3, Rvec3) {
     let w = 1.0 - u - v;
-        
-    if t < ray.t_min || t > ray.t_max || u < 0.0 || v < 0.0 || w < 0.0 {
+    let position = w * a + u * b + v * c;
+    (position, gamma(7) * ((w * a).abs() + (u * b).abs() + (v * c).abs()))
+}
+
+/// The distance along the ray to the triangle and the barycentric coordinates of the hit
+fn intersect_triangle(triangle: TriangleId, mesh: MeshId, ray: &Ray, scene_data: &SceneData)
+    -> Option<(Real, Real, Real)>
+{
+    count_primitive_tests(1);
+    let (a, b, c) = triangle_positions(triangle, &scene_data.mesh_table[mesh.to_index()]);
+    intersect_triangle_positions(&a, &b, &c, ray, &RayShear::new(&ray.direction))
+}
+
+pub(crate) fn triangle_positions(triangle: TriangleId, mesh: &Mesh) -> (Rvec3, Rvec3, Rvec3) {
+    let position = |k: usize| mesh.vertices[mesh.indices[triangle.to_index() + k] as usize].position;
+    (position(0), position(1), position(2))
+}
+
+/// The ray seen along the largest component of its direction, to hit triangles without gaps between them
+pub(crate) struct RayShear {
+    /// Permutation of the axes that puts the largest component of the direction in z
+    pub axes: [usize; 3],
+    /// The shear that makes the direction (0, 0, 1) after the permutation, and the scaling of z
+    pub shear: [Real; 3],
+}
+
+impl RayShear {
+    pub(crate) fn new(direction: &Rvec3) -> Self {
+        let kz = direction.iamax();
+        let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+        if direction[kz] < 0.0 {
+            std::mem::swap(&mut kx, &mut ky); // Keep the winding of the triangles
+        }
+        let sz = 1.0 / direction[kz];
+        RayShear {axes: [kx, ky, kz], shear: [direction[kx] * sz, direction[ky] * sz, sz]}
+    }
+}
+
+/// Watertight intersection: a ray that goes through an edge or a vertex shared by several triangles hits at least
+/// one of them. Both faces of the triangles are hit, whatever the order of their corners.
+// https://jcgt.org/published/0002/01/05/paper.pdf
+pub(crate) fn intersect_triangle_positions(a: &Rvec3, b: &Rvec3, c: &Rvec3, ray: &Ray, shear: &RayShear)
+    -> Option<(Real, Real, Real)>
+{
+    let [kx, ky, kz] = shear.axes;
+    let [sx, sy, sz] = shear.shear;
+
+    // Move the corners to the space where the ray starts at the origin and goes along z. The corners shared by
+    // several triangles are moved exactly the same way for all of them.
+    let (a, b, c) = (a - ray.origin, b - ray.origin, c - ray.origin);
+    let (ax, ay) = (a[kx] - sx * a[kz], a[ky] - sy * a[kz]);
+    let (bx, by) = (b[kx] - sx * b[kz], b[ky] - sy * b[kz]);
+    let (cx, cy) = (c[kx] - sx * c[kz], c[ky] - sy * c[kz]);
+
+    // Twice the signed areas of the triangles made by the ray and each edge. A shared edge gives the same area with
+    // opposite signs to its two triangles, so the ray cannot slip between them.
+    let u = cx * by - cy * bx;
+    let v = ax * cy - ay * cx;
+    let w = bx * ay - by * ax;
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None
+    }
+    let det = u + v + w;
+    if det == 0.0 {
         return None
     }
 
-    // Interpolate the normals and texture coordinates
-    let position = ray.at(t);
-    let normal = w * triangle.0.normal + u * triangle.1.normal + v * triangle.2.normal;
-    let uv = w * triangle.0.uv + u * triangle.1.uv + v * triangle.2.uv;
-    Some((Hit {t, position, normal, uv}, scene_data.mesh_table[mesh.to_index()].material))
+    let inv_det = 1.0 / det;
+    let t = (u * (sz * a[kz]) + v * (sz * b[kz]) + w * (sz * c[kz])) * inv_det;
+    if t < ray.t_min || t > ray.t_max {
+        return None
+    }
+    Some((t, v * inv_det, w * inv_det))
+}
+
+/// The number of triangles of a same mesh at the start of the hittables, up to four
+fn triangle_run(hittables: &[Hittable]) -> usize {
+    let mesh = match hittables.first() {
+        Some(Hittable::Triangle {mesh, ..}) => *mesh,
+        _ => return 0,
+    };
+    hittables.iter().take(4).take_while(|x| matches!(x, Hittable::Triangle {mesh: m, ..} if m.0 == mesh.0)).count()
+}
+
+/// Up to four triangles of a same mesh that follow each other in a BVH leaf, with their corners gathered when the BVH
+/// is built so that they are hit at once
+#[derive(Debug, Clone)]
+pub(crate) struct TrianglePacket {
+    triangles: [TriangleId; 4],
+    mesh: MeshId,
+    len: u8,
+    corners: Triangles4,
+}
+
+impl TrianglePacket {
+    /// The packets of the runs of triangles of a BVH leaf, see `triangle_run`. `positions` gives the corners of a
+    /// triangle. Returns the index of the first hittable of each run in the leaf with its packet.
+    pub(crate) fn of_leaf(leaf: &[Hittable], positions: &impl Fn(TriangleId, MeshId) -> (Rvec3, Rvec3, Rvec3))
+        -> Vec<(usize, TrianglePacket)>
+    {
+        let mut packets = Vec::new();
+        let mut start = 0;
+        while start < leaf.len() {
+            let run = triangle_run(&leaf[start..]);
+            if run > 1 {
+                packets.push((start, Self::new(&leaf[start..start + run], positions)));
+            }
+            start += run.max(1);
+        }
+        packets
+    }
+
+    fn new(run: &[Hittable], positions: &impl Fn(TriangleId, MeshId) -> (Rvec3, Rvec3, Rvec3)) -> Self {
+        let mut packet = TrianglePacket {
+            triangles: [TriangleId(0); 4],
+            mesh: MeshId(0),
+            len: run.len() as u8,
+            corners: Triangles4::default(),
+        };
+        for lane in 0..4 {
+            // The missing triangles are copies of the first one, they are masked out
+            let (triangle, mesh) = match run.get(lane).unwrap_or(&run[0]) {
+                Hittable::Triangle {triangle, mesh} => (*triangle, *mesh),
+                _ => unreachable!(),
+            };
+            packet.triangles[lane] = triangle;
+            packet.mesh = mesh;
+            let (a, b, c) = positions(triangle, mesh);
+            for axis in 0..3 {
+                packet.corners.a[axis][lane] = a[axis];
+                packet.corners.b[axis][lane] = b[axis];
+                packet.corners.c[axis][lane] = c[axis];
+            }
+        }
+        packet
+    }
+
+    /// Intersect a ray with the triangles at once
+    fn intersect(&self, ray: &Ray) -> TriangleHits4 {
+        count_primitive_tests(self.len as u64);
+        let mut hits = match simd::detect() {
+            Some(avx) => avx.intersect_triangles4(&self.corners, ray),
+            None => simd::Scalar.intersect_triangles4(&self.corners, ray),
+        };
+        hits.0 &= (1 << self.len) - 1;
+        hits
+    }
+}
+
+/// The BVH of the mesh of an instance and the ray in the local space of the mesh
+fn instance_local_ray<'a>(instance: &Instance, ray: &Ray, scene_data: &'a SceneData) -> (&'a Bvh, Ray) {
+    let bvh = scene_data.mesh_table[instance.mesh.to_index()].bvh.as_ref()
+        .expect("Build the BVH of a mesh before instancing it");
+
+    // Hit the mesh in its local space, where distances are divided by the scale
+    let to_local = instance.transformation.inverse();
+    let local_ray = Ray {
+        origin: to_local.transform_point(&ray.origin),
+        direction: to_local.transform_direction(&ray.direction),
+        t_min: ray.t_min * to_local.scale,
+        t_max: ray.t_max * to_local.scale,
+    };
+    (bvh, local_ray)
+}
+
+fn hit_instance(instance_id: InstanceId, ray: &Ray, scene_data: &SceneData) -> Option<(Hit, MaterialId)> {
+    let instance = &scene_data.instance_table[instance_id.to_index()];
+    let (bvh, local_ray) = instance_local_ray(instance, ray, scene_data);
+    let (mut hit, material) = bvh.hit(&local_ray, scene_data)?;
+
+    // Bring the hit back into the world
+    hit.t *= instance.transformation.scale;
+    let (position, position_error) =
+        instance.transformation.transform_point_with_error(&hit.position, &hit.position_error);
+    hit.position = position;
+    hit.position_error = position_error;
+    hit.normal = instance.transformation.transform_direction(&hit.normal);
+    hit.geometric_normal = instance.transformation.transform_direction(&hit.geometric_normal);
+    hit.tangent = instance.transformation.transform_direction(&hit.tangent);
+    hit.bitangent = instance.transformation.transform_direction(&hit.bitangent);
+    if let Primitive::Triangle {instance, ..} = &mut hit.primitive {
+        *instance = Some(instance_id);
+    }
+    Some((hit, instance.material.unwrap_or(material)))
+}
+
+fn occluded_instance(instance: InstanceId, ray: &Ray, scene_data: &SceneData) -> bool {
+    let (bvh, local_ray) = instance_local_ray(&scene_data.instance_table[instance.to_index()], ray, scene_data);
+    bvh.occluded(&local_ray, scene_data)
 }
 
 fn hit_list(list: &[Hittable], ray: &Ray, scene_data: &SceneData) -> Option<(Hit, MaterialId)> {
@@ -119,6 +398,59 @@ fn hit_list(list: &[Hittable], ray: &Ray, scene_data: &SceneData) -> Option<(Hit
     hit
 }
 
+// ------------------------------------------- Hit of BVH leaves -------------------------------------------
+
+/// Hit the hittables of a BVH leaf, the triangles of a same mesh that follow each other are hit four at a time with
+/// the packets of the leaf
+pub(crate) fn hit_leaf(leaf: &[Hittable], packets: &[TrianglePacket], ray: &Ray, scene_data: &SceneData)
+    -> Option<(Hit, MaterialId)>
+{
+    let mut hit = None;
+    let mut ray = ray.clone();
+    let mut rest = leaf;
+    let mut packets = packets.iter();
+    while !rest.is_empty() {
+        let run = triangle_run(rest);
+        let new_hit = if run > 1 {
+            let packet = packets.next().expect("Each run of triangles has a packet");
+            let (mask, t, u, v) = packet.intersect(&ray);
+            // The closest hit, the last one among equals like when the triangles are hit one at a time
+            let lane = (0..run).filter(|lane| mask & (1 << lane) != 0).reduce(|a, b| if t[b] <= t[a] {b} else {a});
+            lane.map(|lane| shade_triangle(packet.triangles[lane], packet.mesh, &ray, t[lane], u[lane], v[lane],
+                scene_data))
+        } else {
+            rest[0].hit(&ray, scene_data)
+        };
+        if let Some(new_hit) = new_hit {
+            ray.t_max = new_hit.0.t;
+            hit.replace(new_hit);
+        }
+        rest = &rest[run.max(1)..];
+    }
+    hit
+}
+
+/// Whether the ray hits any hittable of a BVH leaf
+pub(crate) fn occluded_leaf(leaf: &[Hittable], packets: &[TrianglePacket], ray: &Ray, scene_data: &SceneData)
+    -> bool
+{
+    let mut rest = leaf;
+    let mut packets = packets.iter();
+    while !rest.is_empty() {
+        let run = triangle_run(rest);
+        let occluded = if run > 1 {
+            packets.next().expect("Each run of triangles has a packet").intersect(ray).0 != 0
+        } else {
+            rest[0].occluded(ray, scene_data)
+        };
+        if occluded {
+            return true
+        }
+        rest = &rest[run.max(1)..];
+    }
+    false
+}
+
 // ------------------------------------------- Bounding box implementation -------------------------------------------
 
 fn bounding_box_sphere(center: &Rvec3, radius: Real) -> AABB {
@@ -129,14 +461,14 @@ fn bounding_box_sphere(center: &Rvec3, radius: Real) -> AABB {
 }
 
 fn bounding_box_triangle(triangle: TriangleId, mesh: MeshId, scene_data: &SceneData) -> AABB {
-    let triangle = scene_data.mesh_table[mesh.to_index()].get_triangle(triangle);
-    let a = triangle.0.position;
-    let b = triangle.1.position;
-    let c = triangle.2.position;
-    AABB {
-        min: vector![a.x.min(b.x).min(c.x), a.y.min(b.y).min(c.y), a.z.min(b.z).min(c.z)],
-        max: vector![a.x.max(b.x).max(c.x), a.y.max(b.y).max(c.y), a.z.max(b.z).max(c.z)],
-    }
+    scene_data.mesh_table[mesh.to_index()].triangle_bounding_box(triangle)
+}
+
+fn bounding_box_instance(instance: InstanceId, scene_data: &SceneData) -> AABB {
+    let instance = &scene_data.instance_table[instance.to_index()];
+    let bvh = scene_data.mesh_table[instance.mesh.to_index()].bvh.as_ref()
+        .expect("Build the BVH of a mesh before instancing it");
+    instance.transformation.transform_aabb(&bvh.bounding_box())
 }
 
 fn bounding_box_list(list: &[Hittable], scene_data: &SceneData) -> AABB {
@@ -145,3 +477,205 @@ fn bounding_box_list(list: &[Hittable], scene_data: &SceneData) -> AABB {
     }
     list.iter().skip(1).fold(list[0].bounding_box(scene_data), |aabb, x| aabb.union(&x.bounding_box(scene_data)))
 }
+
+// ------------------------------------------- Tests -------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::randomness::*;
+    use crate::simd::Scalar;
+
+    type Triangle = (Rvec3, Rvec3, Rvec3);
+
+    /// Triangles around a center vertex, with random winding, rotated and moved to awkward coordinates
+    fn fan(num_triangles: usize, rng: &mut Randomizer) -> (Rvec3, Vec<Rvec3>, Vec<Triangle>) {
+        let transformation = Transformation::rotation(&rng.sample(UnitSphere), rng.sample(ClosedRange(0.0, TAU)))
+            .then(&Transformation::scaling(rng.sample(ClosedRange(0.1, 10.0))))
+            .then(&Transformation::translation(&(rng.sample(UnitBall) * 100.0)));
+        let center = transformation.transform_point(&vector![0.0, 0.0, 0.0]);
+        let outer = (0..num_triangles).map(|i| {
+            let angle = TAU * i as Real / num_triangles as Real;
+            transformation.transform_point(&vector![angle.cos(), angle.sin(), 0.0])
+        }).collect::<Vec<_>>();
+        let triangles = (0..num_triangles).map(|i| {
+            let (a, b) = (outer[i], outer[(i + 1) % num_triangles]);
+            if rng.gen() {(center, a, b)} else {(center, b, a)}
+        }).collect();
+        (center, outer, triangles)
+    }
+
+    /// A ray toward the target from a random direction
+    fn ray_toward(target: &Rvec3, rng: &mut Randomizer) -> Ray {
+        let origin = target + rng.sample(UnitSphere) * rng.sample(ClosedRange(0.5, 50.0));
+        Ray {origin, direction: target - origin, t_min: 0.0, t_max: INFINITY}
+    }
+
+    fn count_hits(triangles: &[Triangle], ray: &Ray) -> usize {
+        let shear = RayShear::new(&ray.direction);
+        triangles.iter().filter(|(a, b, c)| intersect_triangle_positions(a, b, c, ray, &shear).is_some()).count()
+    }
+
+    fn count_hits_lanes(lanes: impl Lanes, triangles: &[Triangle], ray: &Ray) -> usize {
+        triangles.chunks(4).map(|chunk| {
+            let mut packet = Triangles4::default();
+            for lane in 0..4 {
+                let (a, b, c) = chunk.get(lane).unwrap_or(&chunk[0]);
+                for axis in 0..3 {
+                    packet.a[axis][lane] = a[axis];
+                    packet.b[axis][lane] = b[axis];
+                    packet.c[axis][lane] = c[axis];
+                }
+            }
+            let mask = lanes.intersect_triangles4(&packet, ray).0 & ((1 << chunk.len()) - 1);
+            mask.count_ones() as usize
+        }).sum()
+    }
+
+    fn assert_hit(triangles: &[Triangle], ray: &Ray) {
+        assert!(count_hits(triangles, ray) > 0, "The ray {:?} slipped between the triangles", ray);
+        assert!(count_hits_lanes(Scalar, triangles, ray) > 0, "The ray {:?} slipped between the triangles", ray);
+        if let Some(avx) = simd::detect() {
+            assert!(count_hits_lanes(avx, triangles, ray) > 0, "The ray {:?} slipped between the triangles", ray);
+        }
+    }
+
+    #[test]
+    fn rays_through_shared_edges_hit() {
+        let mut rng = Randomizer::from_seed([1; 32]);
+        for _ in 0..200 {
+            let (center, outer, triangles) = fan(7, &mut rng);
+            for _ in 0..100 {
+                let edge = outer[rng.gen_range(0..outer.len())] - center;
+                // Anywhere on the edge, but more often close to the center where the rounding errors are larger
+                let target = center + edge * (10.0 as Real).powf(rng.sample(ClosedRange(-6.0, 0.0)));
+                assert_hit(&triangles, &ray_toward(&target, &mut rng));
+            }
+        }
+    }
+
+    #[test]
+    fn rays_through_shared_vertices_hit() {
+        let mut rng = Randomizer::from_seed([2; 32]);
+        for _ in 0..200 {
+            let (center, _, triangles) = fan(rng.gen_range(3..12), &mut rng);
+            for _ in 0..100 {
+                assert_hit(&triangles, &ray_toward(&center, &mut rng));
+            }
+        }
+    }
+
+    #[test]
+    fn rays_beside_triangles_miss() {
+        let mut rng = Randomizer::from_seed([3; 32]);
+        for _ in 0..200 {
+            let (center, outer, triangles) = fan(7, &mut rng);
+            for _ in 0..100 {
+                let target = center + (outer[rng.gen_range(0..outer.len())] - center) * 1.5;
+                assert_eq!(count_hits(&triangles, &ray_toward(&target, &mut rng)), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn both_faces_hit() {
+        let (a, b, c) = (vector![0.0, 0.0, 0.0], vector![1.0, 0.0, 0.0], vector![0.0, 1.0, 0.0]);
+        for (origin, direction) in [(vector![0.2, 0.3, 1.0], vector![0.0, 0.0, -1.0]), (vector![0.2, 0.3, -1.0],
+            vector![0.0, 0.0, 1.0])]
+        {
+            let ray = Ray {origin, direction, t_min: 0.0, t_max: INFINITY};
+            for (a, b, c) in [(a, b, c), (a, c, b)] {
+                let (t, u, v) = intersect_triangle_positions(&a, &b, &c, &ray, &RayShear::new(&direction)).unwrap();
+                let expected_uv = if b.x > 0.0 {(0.2, 0.3)} else {(0.3, 0.2)};
+                assert!((t - 1.0).abs() < 1e-12);
+                assert!((u - expected_uv.0).abs() < 1e-12 && (v - expected_uv.1).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn lanes_match_scalar() {
+        let mut rng = Randomizer::from_seed([4; 32]);
+        let avx = match simd::detect() {
+            Some(avx) => avx,
+            None => return,
+        };
+        for _ in 0..10000 {
+            let mut packet = Triangles4::default();
+            for corner in [&mut packet.a, &mut packet.b, &mut packet.c] {
+                for axis in corner.iter_mut() {
+                    *axis = [(); 4].map(|_| rng.sample(ClosedRange(-1.0, 1.0)));
+                }
+            }
+            let ray = ray_toward(&(rng.sample(UnitBall) * 0.5), &mut rng);
+            let (scalar, avx) = (Scalar.intersect_triangles4(&packet, &ray), avx.intersect_triangles4(&packet, &ray));
+            assert_eq!(scalar.0, avx.0);
+            for lane in (0..4).filter(|lane| scalar.0 & (1 << lane) != 0) {
+                assert_eq!((scalar.1[lane], scalar.2[lane], scalar.3[lane]), (avx.1[lane], avx.2[lane], avx.3[lane]));
+            }
+        }
+    }
+
+    #[test]
+    fn spawned_rays_leave_the_surface() {
+        let mut rng = Randomizer::from_seed([5; 32]);
+        for _ in 0..2000 {
+            // Any scale, far from the origin
+            let scale = (10.0 as Real).powf(rng.sample(ClosedRange(-4.0, 4.0)));
+            let offset = rng.sample(UnitBall) * scale * 1000.0;
+            let [a, b, c] = [(); 3].map(|_| offset + rng.sample(UnitBall) * scale);
+            let ray = ray_toward(&((a + b + c) / 3.0), &mut rng);
+            let (t, u, v) = match intersect_triangle_positions(&a, &b, &c, &ray, &RayShear::new(&ray.direction)) {
+                Some(hit) => hit,
+                None => continue,
+            };
+            let (position, position_error) = triangle_point(&a, &b, &c, u, v);
+            let normal = (b - a).cross(&(c - a)).normalize();
+            let (tangent, bitangent) = orthonormal_basis(&normal);
+            let hit = Hit {t, position, position_error, normal, geometric_normal: normal, front_face: true, tangent,
+                bitangent, uv: vector![0.0, 0.0], primitive: Primitive::None};
+            for _ in 0..10 {
+                let spawned = hit.spawn_ray(&rng.sample(UnitSphere));
+                let shear = RayShear::new(&spawned.direction);
+                assert!(intersect_triangle_positions(&a, &b, &c, &spawned, &shear).is_none(),
+                    "The ray {:?} hit the triangle it leaves", spawned);
+            }
+
+            let (center, radius) = (offset, scale);
+            let (position, position_error) = sphere_point(&center, radius, &(center + rng.sample(UnitSphere) * radius));
+            let normal = (position - center).normalize();
+            let (tangent, bitangent) = orthonormal_basis(&normal);
+            let hit = Hit {t, position, position_error, normal, geometric_normal: normal, front_face: true, tangent,
+                bitangent, uv: vector![0.0, 0.0], primitive: Primitive::None};
+            let outward = rng.sample(UnitSphere);
+            let spawned = hit.spawn_ray(&if outward.dot(&normal) > 0.0 {outward} else {-outward});
+            assert!(intersect_sphere(&center, radius, &spawned).is_none(), "The ray {:?} hit the sphere it leaves",
+                spawned);
+        }
+    }
+
+    #[test]
+    fn shading_normals_fall_back_to_the_face() {
+        let vertex = |position: Rvec3, normal: Rvec3| {
+            Vertex {position, normal, tangent: Rvec3::zeros(), bitangent_sign: 1.0, uv: vector![0.0, 0.0]}
+        };
+        let corners = [vector![0.0, 0.0, 0.0], vector![1.0, 0.0, 0.0], vector![0.0, 1.0, 0.0]];
+        let face = vector![0.0, 0.0, 1.0];
+        let with_normals = |normals: [Rvec3; 3]| {
+            (vertex(corners[0], normals[0]), vertex(corners[1], normals[1]), vertex(corners[2], normals[2]))
+        };
+
+        // Missing vertex normals
+        assert_eq!(triangle_normals(&with_normals([Rvec3::zeros(); 3]), 0.2, 0.3), (face, face));
+
+        // Vertex normals that cancel out
+        let (up, down) = (vector![0.0, 1.0, 0.0], vector![0.0, -1.0, 0.0]);
+        assert_eq!(triangle_normals(&with_normals([up, down, Rvec3::zeros()]), 0.5, 0.0), (face, face));
+
+        // Interpolated normals are normalized, and the geometric normal goes on their side
+        let tilted = vector![1.0, 0.0, -1.0].normalize();
+        let (normal, geometric_normal) = triangle_normals(&with_normals([-face, -face, tilted]), 0.2, 0.5);
+        assert!((normal.norm() - 1.0).abs() < 1e-12);
+        assert_eq!(geometric_normal, -face);
+    }
+}
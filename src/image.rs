@@ -137,6 +137,32 @@ pub mod tga {
     }
 }
 
+pub mod pfm {
+    use super::*;
+    use crate::utility::Color;
+    use std::fs::File;
+    use std::io::{Write, BufWriter};
+    use std::error::Error;
+
+    // See http://www.pauldebevec.com/Research/HDR/PFM/
+    pub fn save(image: &Array2d<Color>, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        // Write header, a negative scale means little endian
+        write!(file, "PF\n{} {}\n-1.0\n", image.width(), image.height())?;
+
+        // Write data, from the bottom row to the top row
+        for y in 0..image.height {
+            for x in 0..image.width {
+                for channel in image.get(x, y).iter() {
+                    file.write_all(&(*channel as f32).to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 // ------------------------------------------- Image tiling -------------------------------------------
 
 #[derive(Debug, Clone)]
@@ -7,4 +7,10 @@ pub mod bvh;
 pub mod texture;
 pub mod render;
 pub mod randomness;
-pub mod mesh;
\ No newline at end of file
+pub mod mesh;
+pub mod mtl;
+pub mod light;
+pub mod scene_file;
+pub mod validate;
+pub mod cache;
+pub mod simd;
\ No newline at end of file
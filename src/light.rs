@@ -0,0 +1,259 @@
+/*
+In this file:
+- Lights = emissive primitives that can be sampled explicitly
+- Light sampling implementations
+- Gathering of the lights of a scene
+*/
+
+use crate::utility::*;
+use crate::randomness::*;
+use crate::render::SceneData;
+use crate::hittable::{Hittable, Primitive, sphere_point, sphere_tangents, triangle_point};
+use crate::material::MaterialId;
+use crate::mesh::*;
+
+// ------------------------------------------- Light -------------------------------------------
+
+#[derive(Debug, Clone)]
+pub enum Light {
+    Sphere {center: Rvec3, radius: Real, material: MaterialId},
+    /// The material is the one of the instance if the triangle is instanced
+    Triangle {triangle: TriangleId, mesh: MeshId, instance: Option<InstanceId>, material: MaterialId},
+}
+
+/// A point sampled on a light, as seen from a point of the scene
+pub struct LightSample {
+    /// Unit vector from the origin to the sampled point
+    pub direction: Rvec3,
+    pub distance: Real,
+    /// Probability density of the sampled direction, with respect to solid angle
+    pub pdf: Real,
+    /// The sampled point on the surface of the light
+    pub hit: Hit,
+    pub material: MaterialId,
+}
+
+impl Light {
+    /// Pick a point on the light that is potentially visible from the origin
+    pub fn sample(&self, origin: &Rvec3, scene_data: &SceneData, rng: &mut Randomizer) -> Option<LightSample> {
+        match self {
+            Self::Sphere {center, radius, material} => sample_sphere(center, *radius, *material, origin, rng),
+            Self::Triangle {triangle, mesh, instance, material}
+                => sample_triangle(*triangle, *mesh, *instance, *material, origin, scene_data, rng),
+        }
+    }
+
+    /// Probability density, with respect to solid angle, that `sample` picks the given point of the light
+    pub fn pdf(&self, origin: &Rvec3, hit: &Hit, scene_data: &SceneData) -> Real {
+        match self {
+            Self::Sphere {center, radius, ..} => pdf_sphere(center, *radius, origin, hit),
+            Self::Triangle {triangle, mesh, instance, ..}
+                => pdf_triangle(*triangle, *mesh, *instance, origin, hit, scene_data),
+        }
+    }
+
+    /// The light made of the primitive that was hit, if its material is a light
+    pub fn from_hit(hit: &Hit, material: MaterialId, scene_data: &SceneData) -> Option<Light> {
+        if !scene_data.material_table[material.to_index()].is_light() {
+            return None
+        }
+        match hit.primitive {
+            Primitive::None => None,
+            Primitive::Sphere {center, radius} => Some(Light::Sphere {center, radius, material}),
+            Primitive::Triangle {triangle, mesh, instance}
+                => Some(Light::Triangle {triangle, mesh, instance, material}),
+        }
+    }
+}
+
+// ------------------------------------------- Light sampling implementations -------------------------------------------
+
+fn sample_sphere(center: &Rvec3, radius: Real, material: MaterialId, origin: &Rvec3, rng: &mut Randomizer)
+    -> Option<LightSample>
+{
+    let to_center = center - origin;
+    let dist_squared = to_center.norm_squared();
+    if dist_squared <= radius * radius {
+        // The origin is inside the sphere, sample its whole area instead
+        let normal = rng.sample(UnitSphere);
+        let (position, position_error) = sphere_point(center, radius, &(center + radius * normal));
+        let (tangent, bitangent) = sphere_tangents(&normal);
+        let hit = Hit {
+            t: 0.0,
+            position,
+            position_error,
+            normal,
+            geometric_normal: normal,
+            front_face: (position - origin).dot(&normal) < 0.0,
+            tangent,
+            bitangent,
+            uv: vector![0.5 - normal.z.atan2(normal.x) / TAU, normal.y.asin() / PI + 0.5],
+            primitive: Primitive::Sphere {center: *center, radius},
+        };
+        return sample_from_area(origin, hit, 4.0 * PI * radius * radius, material)
+    }
+
+    // Sample uniformly the cone of directions subtended by the sphere
+    // https://www.pbr-book.org/3ed-2018/Light_Transport_I_Surface_Reflection/Sampling_Light_Sources
+    let dist = dist_squared.sqrt();
+    let w = to_center / dist;
+    let (u, v) = orthonormal_basis(&w);
+    let one_minus_cos_theta_max = sphere_cone_size(dist_squared, radius);
+    let cos_theta = 1.0 - rng.gen::<Real>() * one_minus_cos_theta_max;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = TAU * rng.gen::<Real>();
+    let direction = (phi.cos() * sin_theta * u + phi.sin() * sin_theta * v + cos_theta * w).normalize();
+
+    // Find the point on the sphere in that direction
+    let half_b = direction.dot(&to_center);
+    let distance = half_b - (half_b * half_b - dist_squared + radius * radius).max(0.0).sqrt();
+    let (position, position_error) = sphere_point(center, radius, &(origin + distance * direction));
+    let normal = (position - center).normalize();
+    let uv = vector![0.5 - normal.z.atan2(normal.x) / TAU, normal.y.asin() / PI + 0.5];
+    let primitive = Primitive::Sphere {center: *center, radius};
+    let (tangent, bitangent) = sphere_tangents(&normal);
+    let hit = Hit {t: distance, position, position_error, normal, geometric_normal: normal, front_face: true, tangent,
+        bitangent, uv, primitive};
+
+    Some(LightSample {direction, distance, pdf: 1.0 / (TAU * one_minus_cos_theta_max), hit, material})
+}
+
+fn pdf_sphere(center: &Rvec3, radius: Real, origin: &Rvec3, hit: &Hit) -> Real {
+    let dist_squared = (center - origin).norm_squared();
+    if dist_squared <= radius * radius {
+        pdf_from_area(origin, &hit.position, &hit.geometric_normal, 4.0 * PI * radius * radius)
+    } else {
+        1.0 / (TAU * sphere_cone_size(dist_squared, radius))
+    }
+}
+
+/// One minus the cosine of the half-angle of the cone subtended by a sphere, precise even for tiny cones
+fn sphere_cone_size(dist_squared: Real, radius: Real) -> Real {
+    let sin2_theta_max = radius * radius / dist_squared;
+    let cos_theta_max = (1.0 - sin2_theta_max).max(0.0).sqrt();
+    sin2_theta_max / (1.0 + cos_theta_max)
+}
+
+/// The positions of the corners of a triangle in the world
+fn triangle_positions(triangle: TriangleId, mesh: MeshId, instance: Option<InstanceId>, scene_data: &SceneData)
+    -> [Rvec3; 3]
+{
+    let triangle = scene_data.mesh_table[mesh.to_index()].get_triangle(triangle);
+    let positions = [triangle.0.position, triangle.1.position, triangle.2.position];
+    match instance {
+        None => positions,
+        Some(instance) => {
+            let transformation = &scene_data.instance_table[instance.to_index()].transformation;
+            positions.map(|x| transformation.transform_point(&x))
+        }
+    }
+}
+
+fn sample_triangle(triangle: TriangleId, mesh: MeshId, instance: Option<InstanceId>, material: MaterialId,
+    origin: &Rvec3, scene_data: &SceneData, rng: &mut Randomizer) -> Option<LightSample>
+{
+    let primitive = Primitive::Triangle {triangle, mesh, instance};
+    let [a, b, c] = triangle_positions(triangle, mesh, instance, scene_data);
+    let triangle = scene_data.mesh_table[mesh.to_index()].get_triangle(triangle);
+    let cross = (b - a).cross(&(c - a));
+    let area = 0.5 * cross.norm();
+    if area < SMOL {
+        return None
+    }
+
+    // Uniform barycentric coordinates
+    let sqrt_r = rng.gen::<Real>().sqrt();
+    let u = sqrt_r * rng.gen::<Real>();
+    let v = 1.0 - sqrt_r;
+    let w = 1.0 - u - v;
+    // The point is computed where the triangle is stored, to know its rounding errors
+    let (position, position_error) =
+        triangle_point(&triangle.0.position, &triangle.1.position, &triangle.2.position, u, v);
+    let (position, position_error) = match instance {
+        None => (position, position_error),
+        Some(instance) => scene_data.instance_table[instance.to_index()].transformation
+            .transform_point_with_error(&position, &position_error),
+    };
+    let uv = w * triangle.0.uv + u * triangle.1.uv + v * triangle.2.uv;
+    let normal = cross.normalize();
+    let front_face = (position - origin).dot(&normal) < 0.0;
+    let (tangent, bitangent) = orthonormal_basis(&normal);
+    let hit = Hit {t: 0.0, position, position_error, normal, geometric_normal: normal, front_face, tangent, bitangent,
+        uv, primitive};
+    sample_from_area(origin, hit, area, material)
+}
+
+fn pdf_triangle(triangle: TriangleId, mesh: MeshId, instance: Option<InstanceId>, origin: &Rvec3, hit: &Hit,
+    scene_data: &SceneData) -> Real
+{
+    let [a, b, c] = triangle_positions(triangle, mesh, instance, scene_data);
+    let cross = (b - a).cross(&(c - a));
+    let area = 0.5 * cross.norm();
+    if area < SMOL {
+        return 0.0
+    }
+    pdf_from_area(origin, &hit.position, &cross.normalize(), area)
+}
+
+/// Convert a point sampled uniformly on a surface into a light sample with a solid angle density
+fn sample_from_area(origin: &Rvec3, mut hit: Hit, area: Real, material: MaterialId) -> Option<LightSample> {
+    let to_light = hit.position - origin;
+    let distance = to_light.norm();
+    if distance < SMOL {
+        return None
+    }
+    let direction = to_light / distance;
+    let pdf = pdf_from_area(origin, &hit.position, &hit.geometric_normal, area);
+    if pdf <= 0.0 {
+        return None
+    }
+    hit.t = distance;
+    Some(LightSample {direction, distance, pdf, hit, material})
+}
+
+/// Solid angle density of a point sampled uniformly on a surface
+fn pdf_from_area(origin: &Rvec3, position: &Rvec3, normal: &Rvec3, area: Real) -> Real {
+    let to_light = position - origin;
+    let dist_squared = to_light.norm_squared();
+    let cos_light = normal.dot(&to_light).abs() / dist_squared.sqrt();
+    if cos_light < SMOL {
+        return 0.0
+    }
+    dist_squared / (cos_light * area)
+}
+
+// ------------------------------------------- Gathering the lights -------------------------------------------
+
+/// Find all the primitives of the scene whose material is a light
+pub fn collect_lights(root: &Hittable, scene_data: &SceneData) -> Vec<Light> {
+    let mut lights = Vec::new();
+    collect_lights_rec(root, scene_data, &mut lights);
+    lights
+}
+
+fn collect_lights_rec(hittable: &Hittable, scene_data: &SceneData, lights: &mut Vec<Light>) {
+    let is_light = |material: MaterialId| scene_data.material_table[material.to_index()].is_light();
+    match hittable {
+        Hittable::Sphere {center, radius, material} => if is_light(*material) {
+            lights.push(Light::Sphere {center: *center, radius: *radius, material: *material})
+        },
+        Hittable::Triangle {triangle, mesh} => {
+            let material = scene_data.mesh_table[mesh.to_index()].material;
+            if is_light(material) {
+                lights.push(Light::Triangle {triangle: *triangle, mesh: *mesh, instance: None, material})
+            }
+        }
+        Hittable::Instance(instance_id) => {
+            let instance = &scene_data.instance_table[instance_id.to_index()];
+            let mesh = &scene_data.mesh_table[instance.mesh.to_index()];
+            let material = instance.material.unwrap_or(mesh.material);
+            if is_light(material) {
+                lights.extend(mesh.iter_triangles().map(|triangle| {
+                    Light::Triangle {triangle, mesh: instance.mesh, instance: Some(*instance_id), material}
+                }));
+            }
+        }
+        Hittable::List(list) => list.iter().for_each(|x| collect_lights_rec(x, scene_data, lights)),
+        Hittable::Bvh(bvh) => bvh.leaves().iter().for_each(|x| collect_lights_rec(x, scene_data, lights)),
+    }
+}
@@ -2,38 +2,94 @@ use raytracing2::image::*;
 use raytracing2::utility::*;
 use raytracing2::render::*;
 use raytracing2::randomness::*;
+use raytracing2::light::collect_lights;
+use raytracing2::hittable::Hittable;
+use raytracing2::scene_file;
+use raytracing2::validate::validate;
 use std::time::Instant;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use indicatif::ProgressBar;
 
 mod example_scenes;
+mod cli;
 
 fn main() {
-    let (output_width, output_height) = (800, 600);
+    let options = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli::Command::Render(options)) => options,
+        Ok(cli::Command::Help) => {
+            cli::print_help();
+            return
+        }
+        Err(message) => {
+            eprintln!("{}", message);
+            eprintln!("Try --help for more information");
+            std::process::exit(1)
+        }
+    };
+    let (output_width, output_height) = (options.width, options.height);
 
     // Load the scene
-    // let mut scene = example_scenes::three_balls();
-    // let mut scene = example_scenes::two_balls();
-    // let mut scene = example_scenes::more_balls_optimized();
-    // let mut scene = example_scenes::earth();
-    // let mut scene = example_scenes::one_triangle();
-    let mut scene = example_scenes::bunny();
+    let mut scene = match example_scenes::SCENES.iter().find(|(name, _)| *name == options.scene) {
+        Some((_, make_scene)) => make_scene(),
+        None => match scene_file::load(&options.scene) {
+            Ok(scene) => scene,
+            Err(error) => {
+                eprintln!("Could not load the scene: {}", error);
+                eprintln!("Try --help to list the example scenes");
+                std::process::exit(1)
+            }
+        }
+    };
     scene.camera.aspect_ratio = output_width as Real / output_height as Real;
 
+    // Catch the mistakes in the scene before they crash the workers. The scene files were already checked before
+    // building their BVHs, only their warnings are left.
+    let issues = validate(&scene);
+    for issue in issues.iter() {
+        eprintln!("{}: {}", if issue.is_error() {"Error"} else {"Warning"}, issue);
+    }
+    if issues.iter().any(|issue| issue.is_error()) {
+        eprintln!("The scene is invalid");
+        std::process::exit(1)
+    }
+    scene.scene_data.light_table = collect_lights(&scene.root, &scene.scene_data);
+
+    if options.bvh_stats {
+        if let Hittable::Bvh(bvh) = &scene.root {
+            println!("Top level BVH:\n{}", bvh.stats());
+        }
+        for (index, mesh) in scene.scene_data.mesh_table.iter().enumerate() {
+            if let Some(bvh) = &mesh.bvh {
+                println!("BVH of the mesh #{}:\n{}", index, bvh.stats());
+            }
+        }
+    }
+
     // Renderer parameters
-    let max_bounce = 8; 
-    let tile_size = 32;
-    let num_workers = 4;
+    let path_settings = PathSettings {
+        max_bounce: options.max_bounce,
+        min_bounce: options.min_bounce,
+    };
+    let tile_size = options.tile_size;
+    let num_workers = options.num_workers;
+    let heatmap = options.heatmap;
+
+    // Additional outputs, see render::Aov
+    let aovs = options.aovs.clone();
+
+    // Each tile has its own random numbers so that the image does not depend on the order in which tiles are rendered
+    let seed = options.seed.unwrap_or_else(|| Randomizer::from_entropy().gen());
 
     let sampler = Multisampler {
         width: output_width,
         height: output_height,
-        num_samples: 4,
+        num_samples: options.num_samples,
     };
     
     // Put tiles into the job queue
-    let job_queue = Tile::split_in_tiles(output_width, output_height, tile_size, tile_size);
+    let job_queue = Tile::split_in_tiles(output_width, output_height, tile_size, tile_size)
+        .into_iter().enumerate().collect::<Vec<_>>();
     let progress_bar = ProgressBar::new(job_queue.len() as _);
     
     // Wrap the things into arcs
@@ -48,8 +104,9 @@ fn main() {
         let complete_jobs = Arc::clone(&complete_jobs);
         let progress_bar = progress_bar.clone();
         let sampler = sampler.clone();
+        let path_settings = path_settings.clone();
+        let aovs = aovs.clone();
         let scene = Arc::clone(&scene);
-        let mut rng = Randomizer::from_entropy();
 
         thread::spawn(move || {
             loop {
@@ -58,10 +115,9 @@ fn main() {
                     job_queue.lock().unwrap().pop()
                 };
 
-                if let Some(tile) = job {
-                    // Create 3 buffers
-                    let mut color_buffer = Array2d::new(tile.width, tile.height);
-                    let mut foreground_buffer = Array2d::new(tile.width, tile.height);
+                if let Some((tile_index, tile)) = job {
+                    let mut rng = Randomizer::seed_from_u64(seed ^ noise::integer(tile_index as isize, 0, 0, 0) as u64);
+                    let mut tile_buffer = Framebuffer::new(tile.width, tile.height, &aovs);
                     
                     // Walk on each pixel of the tile
                     for tj in 0..tile.height {
@@ -69,26 +125,23 @@ fn main() {
                             // Jitter the sample inside its pixel
                             let samples = sampler.make_uv_jitter(ti + tile.offset_i, tj + tile.offset_j, &mut rng);
                             
-                            // Accumulate the values of each sample
-                            let mut final_color = rgb(0.0, 0.0, 0.0);
-                            let mut foreground = 0.0;
-                            for s in samples {
+                            // Trace each sample and combine them in the pixel
+                            let samples = samples.map(|s| {
                                 let ray = scene.camera.shoot(s, &mut rng);
-                                let trace_out = trace_path(
-                                    &scene.root, &ray, max_bounce, &scene.scene_data, &mut rng, &scene.background
-                                );
-                                final_color += trace_out.final_color;
-                                if trace_out.hit {
-                                    foreground += 1.0;
+                                if heatmap {
+                                    trace_heatmap(&scene.root, &ray, &scene.scene_data)
+                                } else {
+                                    trace_path(
+                                        &scene.root, &ray, &path_settings, &scene.scene_data, &mut rng,
+                                        &scene.background
+                                    )
                                 }
-                            }
-                            // Write the final color which is the average of the samples
-                            *color_buffer.get_mut(ti, tj) = final_color / sampler.num_samples as Real;
-                            *foreground_buffer.get_mut(ti, tj) = foreground / sampler.num_samples as Real;
+                            }).collect::<Vec<_>>();
+                            tile_buffer.set_pixel(ti, tj, &samples, &scene.camera);
                         }
                     }
                     // Push the finished job
-                    complete_jobs.lock().unwrap().push((tile, color_buffer, foreground_buffer));
+                    complete_jobs.lock().unwrap().push((tile, tile_buffer));
                     progress_bar.inc(1);
                 } else {
                     break
@@ -107,23 +160,46 @@ fn main() {
 
     // Combine the tiles into one image
     let complete_jobs = Arc::try_unwrap(complete_jobs).unwrap().into_inner().unwrap();
-    let mut output_image = Array2d::new(output_width, output_height);
-    let transparent_background = false;
-    for (tile, color_buffer, foreground_buffer) in complete_jobs {
-        for tj in 0..tile.height {
-            for ti in 0..tile.width {
-                let mut rgba = to_srgb_u8(color_buffer.get(ti, tj));
-                if transparent_background {
-                    rgba[3] = (255.0 * foreground_buffer.get(ti, tj)) as u8; // Transparent background
-                }
-                *output_image.get_mut(ti + tile.offset_i, tj + tile.offset_j) = rgba;
+    let mut framebuffer = Framebuffer::new(output_width, output_height, &aovs);
+    for (tile, tile_buffer) in complete_jobs {
+        framebuffer.blit(&tile, &tile_buffer);
+    }
+    if heatmap {
+        let num_pixels = (output_width * output_height) as Real;
+        let mut total = rgb(0.0, 0.0, 0.0);
+        for j in 0..output_height {
+            for i in 0..output_width {
+                total += framebuffer.color.get(i, j);
             }
         }
+        println!("Per camera ray: {:.1} box tests, {:.1} primitive tests", total.x / num_pixels, total.y / num_pixels);
+        if options.output_format().unwrap() == cli::OutputFormat::Tga {
+            colorize_heatmap(&mut framebuffer.color);
+        }
     }
 
-    // Save the output in a file
-    let output_name = "output.tga";
-    tga::save(&output_image, output_name).unwrap();
+    // Save the output in a file, and the AOVs next to it
+    let output_name = options.output.as_str();
+    let saved = match options.output_format().unwrap() {
+        cli::OutputFormat::Tga => {
+            let mut output_image = Array2d::new(output_width, output_height);
+            for j in 0..output_height {
+                for i in 0..output_width {
+                    let mut rgba = to_srgb_u8(framebuffer.color.get(i, j));
+                    if options.transparent_background {
+                        rgba[3] = (255.0 * framebuffer.alpha.get(i, j)) as u8; // Transparent background
+                    }
+                    *output_image.get_mut(i, j) = rgba;
+                }
+            }
+            tga::save(&output_image, output_name)
+        }
+        cli::OutputFormat::Pfm => pfm::save(&framebuffer.color, output_name),
+    };
+    if let Err(error) = saved.and_then(|_| framebuffer.save_aovs(options.output_prefix())) {
+        eprintln!("Could not save the output: {}", error);
+        std::process::exit(1)
+    }
 
     // Open the output in the default image viewer
     if cfg!(target_os = "windows") {
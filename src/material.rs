@@ -3,7 +3,8 @@ In this file:
 - Scattering functions
 - Absorption functions
 - Emission functions
-- Material = aggregate of one scattering, one absorption and one emission function
+- Bump functions
+- Material = aggregate of one scattering, one absorption and one emission function, and a bump function
 */
 
 use crate::utility::*;
@@ -15,6 +16,15 @@ declare_index_wrapper!(MaterialId, u32);
 
 // ------------------------------------------- Scattering -------------------------------------------
 
+/// The scattering toward a given direction
+#[derive(Debug, Clone)]
+pub struct ScatterEval {
+    /// BSDF times the cosine term, to be multiplied by the absorption
+    pub bsdf: Real,
+    /// Probability density, with respect to solid angle, that `Scatter::evaluate` samples this direction
+    pub pdf: Real,
+}
+
 #[derive(Debug, Clone)]
 pub enum Scatter {
     None,
@@ -27,11 +37,30 @@ impl Scatter {
     pub fn evaluate(&self, incident: &Ray, hit: &Hit, _scene_data: &SceneData, rng: &mut Randomizer) -> Option<Ray> {
         match self {
             Self::None => None,
-            Self::Lambert => evaluate_lambert(incident, hit, rng),
+            Self::Lambert => evaluate_lambert(hit, rng),
             Self::Metal {fuzziness} => evaluate_metal(incident, hit, rng, *fuzziness),
             Self::Dielectric {refraction_index} => evaluate_dielectric(incident, hit, rng, *refraction_index),
         }
     }
+
+    /// Evaluate the scattering toward an arbitrary direction instead of sampling one.
+    /// Specular scatterings have a zero BSDF and PDF everywhere.
+    pub fn evaluate_direction(&self, incident: &Ray, hit: &Hit, direction: &Rvec3) -> ScatterEval {
+        match self {
+            Self::Lambert => evaluate_direction_lambert(hit, direction),
+            Self::Metal {fuzziness} => evaluate_direction_metal(incident, hit, direction, *fuzziness),
+            Self::None | Self::Dielectric {..} => ScatterEval {bsdf: 0.0, pdf: 0.0},
+        }
+    }
+
+    /// Specular scatterings follow a single direction and cannot be combined with light sampling
+    pub fn is_specular(&self) -> bool {
+        match self {
+            Self::Lambert => false,
+            Self::Metal {fuzziness} => *fuzziness <= 0.0,
+            Self::None | Self::Dielectric {..} => true,
+        }
+    }
 }
 
 // ------------------------------------------- Emission -------------------------------------------
@@ -81,6 +110,32 @@ impl Absorb {
     }
 }
 
+// ------------------------------------------- Bump -------------------------------------------
+
+/// Perturbation of the shading normal, to show details that the geometry does not have
+#[derive(Debug, Clone)]
+pub enum Bump {
+    None,
+    /// Normals in the tangent frame of the hit: the red, green and blue channels go from -1 to 1 along the tangent,
+    /// the bitangent and the normal
+    NormalMap(TextureId),
+    /// Heights from the brightness of a texture, times the strength. The heights are measured in texture coordinates,
+    /// so a strength of 0.001 makes white as high as a thousandth of the texture. They are differentiated along the
+    /// texture coordinates, so the texture must depend on them.
+    BumpMap {texture: TextureId, strength: Real},
+}
+
+impl Bump {
+    /// Perturb the shading normal of the hit and its tangent frame
+    pub fn evaluate(&self, incident: &Ray, hit: &mut Hit, scene_data: &SceneData, rng: &mut Randomizer) {
+        match self {
+            Self::None => (),
+            Self::NormalMap(tid) => apply_normal_map(incident, hit, scene_data, rng, *tid),
+            Self::BumpMap {texture, strength} => apply_bump_map(incident, hit, scene_data, rng, *texture, *strength),
+        }
+    }
+}
+
 // ------------------------------------------- Material -------------------------------------------
 
 #[derive(Debug, Clone)]
@@ -88,6 +143,7 @@ pub struct Material {
     scatter: Scatter,
     absorb: Absorb,
     emit: Emit,
+    bump: Bump,
 }
 
 pub struct MaterialOutput {
@@ -98,7 +154,34 @@ pub struct MaterialOutput {
 
 impl Material {
     pub fn new(scatter: Scatter, absorb: Absorb, emit: Emit) -> Material {
-        Material {scatter, emit, absorb}
+        Material {scatter, emit, absorb, bump: Bump::None}
+    }
+
+    pub fn with_bump(mut self, bump: Bump) -> Material {
+        self.bump = bump;
+        self
+    }
+
+    pub fn scatter(&self) -> &Scatter {
+        &self.scatter
+    }
+
+    pub fn absorb(&self) -> &Absorb {
+        &self.absorb
+    }
+
+    pub fn emit(&self) -> &Emit {
+        &self.emit
+    }
+
+    /// To be applied to the hits before evaluating the material
+    pub fn bump(&self) -> &Bump {
+        &self.bump
+    }
+
+    /// Primitives made of a light material are sampled explicitly by the renderer
+    pub fn is_light(&self) -> bool {
+        matches!(self.emit, Emit::Color(_))
     }
 
     pub fn evaluate(&self, incident: &Ray, hit: &Hit, scene_data: &SceneData, rng: &mut Randomizer) -> MaterialOutput
@@ -112,53 +195,74 @@ impl Material {
 
 // ------------------------------------------- Scattering implementations -------------------------------------------
 
-fn evaluate_lambert(incident: &Ray, hit: &Hit, rng: &mut Randomizer) -> Option<Ray> {
-    if hit.normal.dot(&incident.direction) > 0.0 {
+fn evaluate_lambert(hit: &Hit, rng: &mut Randomizer) -> Option<Ray> {
+    // Both faces reflect, around the normal on the side of the incident ray
+    let scatter_dir = (hit.facing_normal() + rng.sample(UnitSphere)).normalize();
+
+    // The shading normal may tilt the lobe under the actual surface, the rays that would leak through it are absorbed
+    if hit.facing_geometric_normal().dot(&scatter_dir) <= 0.0 {
         return None
     }
-    
-    // Compute the scatter direction with lambertian distribution
-    let scatter_dir = (hit.normal + rng.sample(UnitSphere)).normalize();
-    
-    let scattered = Ray {
-        direction: scatter_dir,
-        origin: hit.position,
-        t_min: RAY_EPSILON,
-        t_max: INFINITY,
-    };
-    Some(scattered)
+
+    Some(hit.spawn_ray(&scatter_dir))
 }
 
-fn evaluate_metal(incident: &Ray, hit: &Hit, rng: &mut Randomizer, fuzziness: Real) -> Option<Ray> {
-    if hit.normal.dot(&incident.direction) > 0.0 {
-        return None
+fn evaluate_direction_lambert(hit: &Hit, direction: &Rvec3) -> ScatterEval {
+    if hit.facing_geometric_normal().dot(direction) <= 0.0 {
+        return ScatterEval {bsdf: 0.0, pdf: 0.0}
     }
 
+    // Cosine-weighted sampling: BSDF * cos = cos / pi = PDF
+    let pdf = hit.facing_normal().dot(direction).max(0.0) / PI;
+    ScatterEval {bsdf: pdf, pdf}
+}
+
+fn evaluate_metal(incident: &Ray, hit: &Hit, rng: &mut Randomizer, fuzziness: Real) -> Option<Ray> {
     // Compute the reflected direction and add random fuzziness
-    let reflect_dir = (reflect(&incident.direction, &hit.normal) + fuzziness * rng.sample(UnitBall)).normalize();
+    let normal = hit.facing_normal();
+    let reflect_dir = (reflect(&incident.direction, &normal) + fuzziness * rng.sample(UnitBall)).normalize();
 
-    // Check that the fuzziness did not push the ray below the surface
-    if hit.normal.dot(&reflect_dir) < 0.0 {
+    // Check that neither the fuzziness nor the shading normal pushed the ray below the surface
+    if hit.facing_geometric_normal().dot(&reflect_dir) <= 0.0 {
         return None
     }
 
-    let reflected = Ray {
-        direction: reflect_dir,
-        origin: hit.position,
-        t_min: RAY_EPSILON,
-        t_max: INFINITY,
-    };
-    Some(reflected)
+    Some(hit.spawn_ray(&reflect_dir))
+}
+
+fn evaluate_direction_metal(incident: &Ray, hit: &Hit, direction: &Rvec3, fuzziness: Real) -> ScatterEval {
+    let zero = ScatterEval {bsdf: 0.0, pdf: 0.0};
+    if hit.facing_geometric_normal().dot(direction) <= 0.0 || fuzziness <= 0.0 {
+        return zero
+    }
+
+    // The direction is sampled by normalizing a point drawn uniformly in a ball centered on the reflected direction.
+    // Integrate the density of that ball along the half-line that supports the direction.
+    let reflect_dir = reflect(&incident.direction, &hit.facing_normal());
+    let b = direction.dot(&reflect_dir);
+    let delta = b * b - reflect_dir.norm_squared() + fuzziness * fuzziness;
+    if delta <= 0.0 {
+        return zero
+    }
+    let t_far = b + delta.sqrt();
+    let t_near = (b - delta.sqrt()).max(0.0);
+    if t_far <= 0.0 {
+        return zero
+    }
+    let pdf = (t_far.powi(3) - t_near.powi(3)) / (4.0 * PI * fuzziness.powi(3));
+
+    // The rays that the fuzziness pushes below the surface are absorbed, so the sampling is exact: BSDF * cos = PDF
+    ScatterEval {bsdf: pdf, pdf}
 }
 
 fn evaluate_dielectric(incident: &Ray, hit: &Hit, rng: &mut Randomizer, refraction_index: Real) -> Option<Ray> {
-    let (eta, normal) = if hit.normal.dot(&incident.direction) > 0.0 {
-        // Interior
-        (refraction_index, -hit.normal)
-    } else {
-        // Exterior
-        (1.0 / refraction_index, hit.normal)
-    };
+    let eta = if hit.front_face {1.0 / refraction_index} else {refraction_index};
+
+    // At grazing angles, the shading normal may face away from the ray, which cannot refract then
+    let mut normal = hit.facing_normal();
+    if normal.dot(&incident.direction) >= 0.0 {
+        normal = hit.facing_geometric_normal();
+    }
 
     let reflectance = {
         let r0 = ((1.0 - eta) / (1.0 + eta)).powi(2);
@@ -170,11 +274,148 @@ fn evaluate_dielectric(incident: &Ray, hit: &Hit, rng: &mut Randomizer, refracti
     } else {
         refract(&incident.direction, &normal, eta).unwrap_or(reflect(&incident.direction, &normal))
     };
-    let bounce = Ray {
-        direction: bounce_direction,
-        origin: hit.position,
-        t_min: RAY_EPSILON,
-        t_max: INFINITY,
+    Some(hit.spawn_ray(&bounce_direction))
+}
+
+// ------------------------------------------- Bump implementations -------------------------------------------
+
+/// Step of the texture coordinates to differentiate the bump maps
+const BUMP_DELTA: Real = 1e-3;
+
+fn apply_normal_map(incident: &Ray, hit: &mut Hit, scene_data: &SceneData, rng: &mut Randomizer, texture: TextureId) {
+    let color = scene_data.texture_table[texture.to_index()].sample(incident, hit, scene_data, rng);
+    let local = 2.0 * color - rgb(1.0, 1.0, 1.0);
+    let normal = local.x * hit.tangent + local.y * hit.bitangent + local.z * hit.normal;
+    set_shading_normal(hit, &normal);
+}
+
+fn apply_bump_map(incident: &Ray, hit: &mut Hit, scene_data: &SceneData, rng: &mut Randomizer, texture: TextureId,
+    strength: Real)
+{
+    let texture = &scene_data.texture_table[texture.to_index()];
+    let mut shifted = hit.clone();
+    let mut height = |uv: Rvec2| {
+        shifted.uv = uv;
+        let color = texture.sample(incident, &shifted, scene_data, rng);
+        strength * (color.x + color.y + color.z) / 3.0
+    };
+    let h = height(hit.uv);
+    let dh_du = (height(hit.uv + vector![BUMP_DELTA, 0.0]) - h) / BUMP_DELTA;
+    let dh_dv = (height(hit.uv + vector![0.0, BUMP_DELTA]) - h) / BUMP_DELTA;
+
+    // The surface moved by h along the normal has the tangents t + dh/du n and b + dh/dv n, if the normal varies
+    // slowly. Their cross product is the new normal.
+    let normal = hit.normal - dh_du * hit.tangent - dh_dv * hit.bitangent;
+    set_shading_normal(hit, &normal);
+}
+
+/// Replace the shading normal of a hit and turn its tangent frame along. The normals that are degenerate or that go
+/// to the other side of the surface are ignored.
+fn set_shading_normal(hit: &mut Hit, normal: &Rvec3) {
+    let length = normal.norm();
+    if length < SMOL || !length.is_finite() || normal.dot(&hit.geometric_normal) <= 0.0 {
+        return
+    }
+    let normal = normal / length;
+    let tangent = hit.tangent - normal * normal.dot(&hit.tangent);
+    let mirrored = hit.normal.cross(&hit.tangent).dot(&hit.bitangent) < 0.0;
+    let (tangent, bitangent) = match tangent.norm() {
+        length if length < SMOL => orthonormal_basis(&normal),
+        length => (tangent / length, normal.cross(&tangent) / length),
     };
-    Some(bounce)
-}
\ No newline at end of file
+    hit.normal = normal;
+    hit.tangent = tangent;
+    hit.bitangent = if mirrored {-bitangent} else {bitangent};
+}
+
+// ------------------------------------------- Tests -------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::Texture;
+    use crate::hittable::Primitive;
+
+    /// A hit on the plane z = 0 seen from above, whose tangent frame is x, y, z, or x, -y, z when it is mirrored
+    fn hit(mirrored: bool) -> Hit {
+        Hit {
+            t: 1.0,
+            position: Rvec3::zeros(),
+            position_error: Rvec3::zeros(),
+            normal: vector![0.0, 0.0, 1.0],
+            geometric_normal: vector![0.0, 0.0, 1.0],
+            front_face: true,
+            tangent: vector![1.0, 0.0, 0.0],
+            bitangent: vector![0.0, if mirrored {-1.0} else {1.0}, 0.0],
+            uv: vector![0.3, 0.4],
+            primitive: Primitive::None,
+        }
+    }
+
+    /// The hit after the bump of a material with the given texture
+    fn bumped(mut hit: Hit, texture: Texture, bump: Bump) -> Hit {
+        let scene_data = SceneData {material_table: Vec::new(), texture_table: vec![texture], mesh_table: Vec::new(),
+            instance_table: Vec::new(), light_table: Vec::new()};
+        let incident = Ray {origin: vector![0.0, 0.0, 1.0], direction: vector![0.0, 0.0, -1.0], t_min: 0.0,
+            t_max: Real::INFINITY};
+        bump.evaluate(&incident, &mut hit, &scene_data, &mut Randomizer::from_seed([7; 32]));
+        hit
+    }
+
+    fn assert_near(a: &Rvec3, b: &Rvec3) {
+        assert!((a - b).norm() < 1e-9, "{:?} is not {:?}", a, b);
+    }
+
+    /// The tangent frame is orthonormal, and mirrored if the bitangent is on the other side
+    fn assert_frame(hit: &Hit, mirrored: bool) {
+        assert!((hit.normal.norm() - 1.0).abs() < 1e-9 && (hit.tangent.norm() - 1.0).abs() < 1e-9);
+        assert!(hit.normal.dot(&hit.tangent).abs() < 1e-9);
+        assert_near(&hit.bitangent, &(if mirrored {-1.0} else {1.0} * hit.normal.cross(&hit.tangent)));
+    }
+
+    #[test]
+    fn flat_normal_map() {
+        let flat = bumped(hit(false), Texture::Solid(rgb(0.5, 0.5, 1.0)), Bump::NormalMap(TextureId(0)));
+        assert_near(&flat.normal, &hit(false).normal);
+        assert_near(&flat.tangent, &hit(false).tangent);
+        assert_near(&flat.bitangent, &hit(false).bitangent);
+    }
+
+    #[test]
+    fn tilted_normal_map() {
+        // (0.5, 0, 1) in the tangent frame
+        let tilted = bumped(hit(false), Texture::Solid(rgb(0.75, 0.5, 1.0)), Bump::NormalMap(TextureId(0)));
+        assert_near(&tilted.normal, &vector![0.5, 0.0, 1.0].normalize());
+        assert_frame(&tilted, false);
+
+        // The mirrored frame stays mirrored, and the bitangent channel follows the mirrored bitangent
+        let tilted = bumped(hit(true), Texture::Solid(rgb(0.5, 0.75, 1.0)), Bump::NormalMap(TextureId(0)));
+        assert_near(&tilted.normal, &vector![0.0, -0.5, 1.0].normalize());
+        assert_frame(&tilted, true);
+    }
+
+    #[test]
+    fn normals_below_the_surface_are_ignored() {
+        // (1, 0, -1) in the tangent frame goes below the geometric surface
+        let below = bumped(hit(false), Texture::Solid(rgb(1.0, 0.5, 0.0)), Bump::NormalMap(TextureId(0)));
+        assert_near(&below.normal, &hit(false).normal);
+        // Also when the shading normal was already tilted away from the geometric normal
+        let mut tilted_hit = hit(false);
+        tilted_hit.geometric_normal = vector![-0.8, 0.0, 0.6];
+        let below = bumped(tilted_hit.clone(), Texture::Solid(rgb(1.0, 0.5, 0.75)), Bump::NormalMap(TextureId(0)));
+        assert_near(&below.normal, &tilted_hit.normal);
+    }
+
+    #[test]
+    fn bump_map_slope() {
+        // The brightness of the texture is (u + v) / 3, so the height rises by strength / 3 along u and v
+        let strength = 0.3;
+        for mirrored in [false, true] {
+            let bump = Bump::BumpMap {texture: TextureId(0), strength};
+            let bumped = bumped(hit(mirrored), Texture::DebugUVs, bump);
+            let expected = hit(mirrored).normal - 0.1 * hit(mirrored).tangent - 0.1 * hit(mirrored).bitangent;
+            assert_near(&bumped.normal, &expected.normalize());
+            assert_frame(&bumped, mirrored);
+        }
+    }
+}
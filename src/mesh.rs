@@ -1,27 +1,80 @@
 use crate::utility::*;
 use crate::material::MaterialId;
-
-// TODO: separate the mesh (= vertices + indices) and the instance (= mesh + transformation + material)
+use crate::hittable::{Hittable, triangle_positions};
+use crate::bvh::{Bvh, BvhStrategy};
+use crate::cache;
+use std::collections::HashMap;
+use std::error::Error;
 
 #[derive(Clone)]
 pub struct Vertex {
     pub position: Rvec3,
+    /// Zero when it is unknown, then the face normal is used instead
     pub normal: Rvec3,
+    /// Direction of increasing u along the surface, perpendicular to the normal, for normal mapping. Zero when it is
+    /// unknown, then any direction perpendicular to the normal is used instead.
+    pub tangent: Rvec3,
+    /// The bitangent is this sign times the cross product of the normal and the tangent. It is -1 where the texture
+    /// is mirrored.
+    pub bitangent_sign: Real,
     pub uv: Rvec2,
 }
 
 declare_index_wrapper!(MeshId, u32);
 declare_index_wrapper!(TriangleId, u32);
+declare_index_wrapper!(InstanceId, u32);
 
 // ------------------------------------------- Mesh storage -------------------------------------------
 
 pub struct Mesh {
+    /// The object or group of the file that the mesh comes from, empty if it has no name
+    pub name: String,
     pub vertices: Vec<Vertex>,
     pub indices: Vec<u32>,
+    /// The material of the triangles, unless an instance overrides it
     pub material: MaterialId,
+    /// The triangles of the mesh in local space, to hit the instances of the mesh. See `Mesh::build_bvh`.
+    pub bvh: Option<Bvh>,
 }
 
 impl Mesh {
+    pub fn new(vertices: Vec<Vertex>, indices: Vec<u32>, material: MaterialId) -> Mesh {
+        Mesh {name: String::new(), vertices, indices, material, bvh: None}
+    }
+
+    pub fn with_name(self, name: &str) -> Mesh {
+        Mesh {name: name.to_string(), ..self}
+    }
+
+    /// Build the BVH of the triangles so that the mesh can be instanced, `mesh_id` is its index in the mesh table
+    pub fn build_bvh(&mut self, mesh_id: MeshId, strategy: BvhStrategy) {
+        let triangles = self.iter_triangles().map(|triangle| Hittable::Triangle {triangle, mesh: mesh_id}).collect();
+        let bounding_boxes = self.iter_triangles().map(|triangle| self.triangle_bounding_box(triangle)).collect();
+        let bvh = Bvh::with_bounding_boxes(triangles, bounding_boxes, strategy,
+            |triangle, _| triangle_positions(triangle, self));
+        self.bvh = Some(bvh);
+    }
+
+    /// Update the BVH after the vertices moved, see `Bvh::refit`. It is rebuilt if refitting degraded it too much.
+    pub fn refit_bvh(&mut self, mesh_id: MeshId) {
+        if let Some(mut bvh) = self.bvh.take() {
+            bvh.refit_with(|x| match x {
+                Hittable::Triangle {triangle, ..} => self.triangle_bounding_box(*triangle),
+                _ => unreachable!(),
+            }, |triangle, _| triangle_positions(triangle, self));
+            if bvh.needs_rebuild() {
+                self.build_bvh(mesh_id, bvh.strategy());
+            } else {
+                self.bvh = Some(bvh);
+            }
+        }
+    }
+
+    pub fn triangle_bounding_box(&self, triangle: TriangleId) -> AABB {
+        let (a, b, c) = self.get_triangle(triangle);
+        AABB::empty().union_point(&a.position).union_point(&b.position).union_point(&c.position)
+    }
+
     pub fn get_triangle(&self, triangle: TriangleId) -> (Vertex, Vertex, Vertex) {
         let a = self.vertices[self.indices[triangle.to_index() + 0] as usize].clone();
         let b = self.vertices[self.indices[triangle.to_index() + 1] as usize].clone();
@@ -34,18 +87,238 @@ impl Mesh {
     }
 }
 
+// ------------------------------------------- Tangents -------------------------------------------
+
+impl Mesh {
+    /// Compute the tangents of the vertices from their texture coordinates, like MikkTSpace so that the normal maps
+    /// baked by other tools look right:
+    /// - The tangent of a vertex is the direction of increasing u on the triangles around it, in the plane of the
+    ///   normal, weighted by the angles of the triangles at the vertex
+    /// - The bitangent sign tells whether the texture is mirrored. The vertices shared by mirrored and unmirrored
+    ///   triangles are split in two.
+    /// - The triangles whose positions or texture coordinates are degenerate have no say. The vertices that only have
+    ///   such triangles take the tangent of another vertex at the same position, if any.
+    pub fn generate_tangents(&mut self) {
+        // Sums of the tangents of the corners by vertex, unmirrored then mirrored, and whether there was any
+        let mut sums = vec![[(Rvec3::zeros(), false); 2]; self.vertices.len()];
+        // Whether the texture is mirrored at each corner, None for the degenerate triangles
+        let mut mirrored_corners = Vec::with_capacity(self.indices.len());
+        for triangle in self.iter_triangles() {
+            let (a, b, c) = self.get_triangle(triangle);
+            let frame = triangle_tangent(&a, &b, &c);
+            let corners = [&a, &b, &c];
+            for k in 0..3 {
+                mirrored_corners.push(frame.map(|(_, _, mirrored)| mirrored));
+                let (tangent, face_normal, mirrored) = match frame {
+                    Some(frame) => frame,
+                    None => continue,
+                };
+                let (vertex, next, previous) = (corners[k], corners[(k + 1) % 3], corners[(k + 2) % 3]);
+                let normal = vertex.normal.try_normalize(SMOL).unwrap_or(face_normal);
+                let project = |x: Rvec3| (x - normal * normal.dot(&x)).try_normalize(SMOL);
+                let sum = &mut sums[self.indices[triangle.to_index() + k] as usize][mirrored as usize];
+                sum.1 = true;
+                let edges = (project(next.position - vertex.position), project(previous.position - vertex.position));
+                if let (Some(tangent), (Some(edge1), Some(edge2))) = (project(tangent), edges) {
+                    sum.0 += edge1.dot(&edge2).clamp(-1.0, 1.0).acos() * tangent;
+                }
+            }
+        }
+
+        // Split the vertices of both orientations, the mirrored triangles get the copy
+        let mut orientations = sums.iter()
+            .map(|[unmirrored, mirrored]| if unmirrored.1 {Some(false)} else if mirrored.1 {Some(true)} else {None})
+            .collect::<Vec<_>>();
+        let mut copies = vec![None; self.vertices.len()];
+        for vertex_id in 0..copies.len() {
+            let [unmirrored, mirrored] = sums[vertex_id];
+            if unmirrored.1 && mirrored.1 {
+                copies[vertex_id] = Some(self.vertices.len() as u32);
+                self.vertices.push(self.vertices[vertex_id].clone());
+                orientations.push(Some(true));
+                sums.push([(Rvec3::zeros(), false), mirrored]);
+            }
+        }
+        for (index, mirrored) in self.indices.iter_mut().zip(mirrored_corners) {
+            if let (Some(true), Some(copy)) = (mirrored, copies[*index as usize]) {
+                *index = copy;
+            }
+        }
+
+        let mut tangents_by_position = HashMap::new();
+        for ((vertex, sums), orientation) in self.vertices.iter_mut().zip(sums.iter()).zip(orientations.iter()) {
+            vertex.tangent = Rvec3::zeros();
+            vertex.bitangent_sign = 1.0;
+            if let Some(mirrored) = *orientation {
+                vertex.tangent = sums[mirrored as usize].0.try_normalize(SMOL).unwrap_or_else(Rvec3::zeros);
+                vertex.bitangent_sign = if mirrored {-1.0} else {1.0};
+                let key = vertex.position.map(|x| x.to_bits());
+                tangents_by_position.insert(key, (vertex.tangent, vertex.bitangent_sign));
+            }
+        }
+        for (vertex, orientation) in self.vertices.iter_mut().zip(orientations) {
+            if orientation.is_some() {
+                continue
+            }
+            if let Some((tangent, sign)) = tangents_by_position.get(&vertex.position.map(|x| x.to_bits())) {
+                let tangent = tangent - vertex.normal * vertex.normal.dot(tangent);
+                vertex.tangent = tangent.try_normalize(SMOL).unwrap_or_else(Rvec3::zeros);
+                vertex.bitangent_sign = *sign;
+            }
+        }
+    }
+}
+
+/// Direction of increasing u on a triangle, the normal of the triangle and whether its texture is mirrored. None if
+/// the triangle or its texture coordinates are degenerate.
+fn triangle_tangent(a: &Vertex, b: &Vertex, c: &Vertex) -> Option<(Rvec3, Rvec3, bool)> {
+    let (edge1, edge2) = (b.position - a.position, c.position - a.position);
+    let (duv1, duv2) = (b.uv - a.uv, c.uv - a.uv);
+    let det = duv1.x * duv2.y - duv2.x * duv1.y;
+    let face_normal = edge1.cross(&edge2).try_normalize(Real::MIN_POSITIVE)?;
+    if det == 0.0 {
+        return None
+    }
+    // Solving edge = du * dp/du + dv * dp/dv for both edges gives dp/du, up to the division by det
+    let tangent = ((edge1 * duv2.y - edge2 * duv1.y) * det.signum()).try_normalize(Real::MIN_POSITIVE)?;
+    Some((tangent, face_normal, det < 0.0))
+}
+
+// ------------------------------------------- Polygons -------------------------------------------
+
+/// Normal of a polygon whose corners may not be exactly in a plane, by Newell's method. Its length is twice the area
+/// of the polygon.
+fn polygon_normal(corners: &[Rvec3]) -> Rvec3 {
+    let n = corners.len();
+    (0..n).map(|k| (corners[k] - corners[0]).cross(&(corners[(k + 1) % n] - corners[0]))).sum()
+}
+
+/// Split a polygon into triangles by ear clipping, so that the concave polygons are covered exactly. The triangles are
+/// the indices of their corners and turn the same way as the polygon. What remains of a polygon that has no ear
+/// because it is not simple is split anyhow.
+pub fn triangulate_polygon(corners: &[Rvec3]) -> Vec<[usize; 3]> {
+    if corners.len() < 3 {
+        return Vec::new()
+    }
+
+    // Work in the plane of the polygon, where its corners turn counterclockwise
+    let normal = polygon_normal(corners);
+    let (tangent, bitangent) = orthonormal_basis(&normal.try_normalize(SMOL).unwrap_or_else(|| vector![0.0, 0.0, 1.0]));
+    let points = corners.iter().map(|x| vector![x.dot(&tangent), x.dot(&bitangent)]).collect::<Vec<Rvec2>>();
+    let area = (0..points.len()).map(|k| points[k].perp(&points[(k + 1) % points.len()])).sum::<Real>();
+    let turn = |a: &Rvec2, b: &Rvec2, c: &Rvec2| area.signum() * (b - a).perp(&(c - a));
+
+    let mut remaining = (0..corners.len()).collect::<Vec<_>>();
+    let mut triangles = Vec::with_capacity(corners.len() - 2);
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let corners_of = |k: usize| [remaining[(k + n - 1) % n], remaining[k], remaining[(k + 1) % n]];
+        // An ear is a convex corner whose triangle has no other corner inside
+        let is_ear = |k: usize| {
+            let [a, b, c] = corners_of(k).map(|x| &points[x]);
+            turn(a, b, c) > 0.0 && remaining.iter().map(|x| &points[*x]).all(|x| {
+                x == a || x == b || x == c || turn(a, b, x) < 0.0 || turn(b, c, x) < 0.0 || turn(c, a, x) < 0.0
+            })
+        };
+        let ear = (0..n).find(|k| is_ear(*k)).unwrap_or(0);
+        triangles.push(corners_of(ear));
+        remaining.remove(ear);
+    }
+    triangles.push([remaining[0], remaining[1], remaining[2]]);
+    triangles
+}
+
+// ------------------------------------------- Mesh cache -------------------------------------------
+
+impl Mesh {
+    /// Encode the name, the vertices, the indices and the BVH, but not the material that is chosen by the scene
+    fn write_cache(&self, writer: &mut cache::Writer) {
+        writer.string(&self.name);
+        writer.len(self.vertices.len());
+        for vertex in self.vertices.iter() {
+            vertex.position.iter().chain(vertex.normal.iter()).chain(vertex.tangent.iter()).chain(vertex.uv.iter())
+                .for_each(|x| writer.real(*x));
+            writer.real(vertex.bitangent_sign);
+        }
+        writer.len(self.indices.len());
+        self.indices.iter().for_each(|x| writer.u32(*x));
+        match &self.bvh {
+            None => writer.u8(0),
+            Some(bvh) => {
+                writer.u8(1);
+                bvh.write_cache(writer, |x, writer| match x {
+                    Hittable::Triangle {triangle, ..} => writer.u32(triangle.0),
+                    _ => unreachable!(),
+                });
+            }
+        }
+    }
+
+    /// Decode a mesh encoded by `write_cache`, that is the mesh `mesh_id` of the scene
+    fn read_cache(reader: &mut cache::Reader, mesh_id: MeshId) -> Result<Mesh, Box<dyn Error>> {
+        let name = reader.string()?;
+        let mut vertices = Vec::with_capacity(reader.len(96)?);
+        for _ in 0..vertices.capacity() {
+            let position = vector![reader.real()?, reader.real()?, reader.real()?];
+            let normal = vector![reader.real()?, reader.real()?, reader.real()?];
+            let tangent = vector![reader.real()?, reader.real()?, reader.real()?];
+            let uv = vector![reader.real()?, reader.real()?];
+            let bitangent_sign = reader.real()?;
+            vertices.push(Vertex {position, normal, tangent, bitangent_sign, uv});
+        }
+        let indices = (0..reader.len(4)?).map(|_| reader.u32()).collect::<Result<Vec<_>, _>>()?;
+        if indices.len() % 3 != 0 || indices.iter().any(|x| *x as usize >= vertices.len()) {
+            return Err("Invalid triangle in the cache file".into())
+        }
+        let mut mesh = Mesh::new(vertices, indices, MaterialId(0)).with_name(&name);
+        mesh.bvh = match reader.u8()? {
+            0 => None,
+            _ => Some(Bvh::read_cache(reader, |reader| {
+                let triangle = reader.u32()?;
+                if triangle % 3 != 0 || triangle as usize >= mesh.indices.len() {
+                    return Err("Invalid triangle in the cache file".into())
+                }
+                Ok(Hittable::Triangle {triangle: TriangleId(triangle), mesh: mesh_id})
+            }, |triangle, _| triangle_positions(triangle, &mesh))?),
+        };
+        Ok(mesh)
+    }
+}
+
+// ------------------------------------------- Mesh instance -------------------------------------------
+
+/// A copy of a mesh placed somewhere in the scene. The copies share the vertices and the BVH of the mesh.
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub mesh: MeshId,
+    /// From the local space of the mesh to the world
+    pub transformation: Transformation,
+    /// Replaces the material of the mesh
+    pub material: Option<MaterialId>,
+}
+
+impl Instance {
+    pub fn new(mesh: MeshId, transformation: Transformation) -> Instance {
+        Instance {mesh, transformation, material: None}
+    }
+
+    pub fn with_material(self, material: MaterialId) -> Instance {
+        Instance {material: Some(material), ..self}
+    }
+}
+
 // ------------------------------------------- Mesh loading -------------------------------------------
 
 mod obj_parser {
     use std::{io::BufRead, error::Error};
     use nom::{
         IResult,
-        bytes::complete::{tag, take_while},
-        sequence::tuple,
-        combinator::{map_res, map, opt},
-        character::complete::space1,
+        bytes::complete::tag,
+        sequence::{tuple, preceded, terminated},
+        combinator::{map_res, map, opt, value, rest, eof},
+        character::complete::{space1, u32 as integer, i64 as signed_integer},
         number::complete::double,
-        multi::separated_list1,
+        multi::{separated_list1, many_m_n},
         branch::alt,
     };
 
@@ -56,48 +329,77 @@ mod obj_parser {
         pub texcoord: Option<u32>,
     }
 
-    fn parse_index(input: &str) -> IResult<&str, Index> {
-        let integer = map_res(take_while(|c: char| c.is_ascii_digit()), |x| u32::from_str_radix(x, 10));
+    /// The indices of a face corner as they are written: position, texcoord and normal. They count from 1, or
+    /// backwards from the last element when they are negative.
+    type RawIndex = (i64, Option<i64>, Option<i64>);
 
+    fn parse_index(input: &str) -> IResult<&str, RawIndex> {
         map_res(
-            separated_list1(tag("/"), opt(integer)),
-            |indices: Vec<Option<u32>>| -> Result<_, &str> {
-                let position = indices.get(0).cloned().flatten().ok_or("Position index not provided").map(|x| x - 1)?;
-                let normal = indices.get(2).cloned().flatten().map(|x| x - 1);
-                let texcoord = indices.get(1).cloned().flatten().map(|x| x - 1);
-                Ok(Index {position, normal, texcoord})
+            separated_list1(tag("/"), opt(signed_integer)),
+            |indices: Vec<Option<i64>>| -> Result<_, &str> {
+                let position = indices.first().cloned().flatten().ok_or("Position index not provided")?;
+                Ok((position, indices.get(1).cloned().flatten(), indices.get(2).cloned().flatten()))
             }
         )(input)
     }
 
-    enum Line {
+    #[derive(Clone)]
+    enum Line<'a> {
         V([f64; 3]),
         Vn([f64; 3]),
         Vt([f64; 2]),
-        F(Vec<Index>),
+        F(Vec<RawIndex>),
+        /// Smoothing group, 0 when it is off
+        S(u32),
+        /// Object or group, named by the rest of the line
+        Group(&'a str),
+        UseMaterial(&'a str),
+        MaterialLibraries(Vec<&'a str>),
     }
     
     fn parse_vec3(input: &str) -> IResult<&str, [f64; 3]> {
         map(tuple((double, space1, double, space1, double)), |(x, _, y, _, z)| [x, y, z])(input)
     }
 
+    /// The second coordinate is optional, and so is the third one, which is ignored
     fn parse_vec2(input: &str) -> IResult<&str, [f64; 2]> {
-        map(tuple((double, space1, double)), |(x, _, y)| [x, y])(input)
+        let coordinate = || opt(preceded(space1, double));
+        map(tuple((double, coordinate(), coordinate())), |(x, y, _)| [x, y.unwrap_or(0.0)])(input)
     }
 
-    fn parse_line(input: &str) -> IResult<&str, Line> {
-        let v = map(tuple((tag("v"), space1, parse_vec3)), |(_, _, v)| Line::V(v));
+    /// The numbers after a position, either its weight or the color of the vertex for some exporters, are ignored
+    fn parse_extra_numbers(input: &str) -> IResult<&str, ()> {
+        value((), many_m_n(0, 4, preceded(space1, double)))(input)
+    }
+
+    /// The rest of the line, if any
+    fn parse_name(input: &str) -> IResult<&str, &str> {
+        alt((preceded(space1, rest), value("", eof)))(input)
+    }
+
+    fn parse_line(input: &str) -> IResult<&str, Line<'_>> {
+        let v = map(tuple((tag("v"), space1, parse_vec3, parse_extra_numbers)), |(_, _, v, _)| Line::V(v));
         let vn = map(tuple((tag("vn"), space1, parse_vec3)), |(_, _, vn)| Line::Vn(vn));
         let vt = map(tuple((tag("vt"), space1, parse_vec2)), |(_, _, vt)| Line::Vt(vt));
         let f = map(tuple((tag("f"), space1, separated_list1(space1, parse_index))), |(_, _, f)| Line::F(f));
+        let s = map(tuple((tag("s"), space1, alt((value(0, tag("off")), integer)))), |(_, _, s)| Line::S(s));
+        let group = map(tuple((alt((tag("o"), tag("g"))), parse_name)), |(_, name)| Line::Group(name));
+        let usemtl = map(tuple((tag("usemtl"), parse_name)), |(_, name)| Line::UseMaterial(name));
+        let mtllib = map(tuple((tag("mtllib"), space1, rest)),
+            |(_, _, files): (_, _, &str)| Line::MaterialLibraries(files.split_whitespace().collect()));
 
-        alt((v, vn, vt, f))(input)
+        // The whole line must be read, the lines with something left are skipped
+        terminated(alt((v, vn, vt, f, s, group, usemtl, mtllib)), eof)(input)
     }
 
     #[derive(Debug, Clone, Copy)]
     pub struct Face {
         pub first_vertex: u32,
         pub num_vertices: u32,
+        /// None when the file has no smoothing groups, 0 when smoothing is off for this face
+        pub smoothing_group: Option<u32>,
+        pub group: u32,
+        pub material: u32,
     }
     
     #[derive(Default, Clone)]
@@ -107,26 +409,96 @@ mod obj_parser {
         pub texcoords: Vec<[f64; 2]>,
         pub vertices: Vec<Index>,
         pub faces: Vec<Face>,
+        /// The names of the objects and groups, the faces before the first one are in an unnamed group
+        pub groups: Vec<String>,
+        /// The names of the materials, the faces before the first one have an unnamed material
+        pub materials: Vec<String>,
+        /// The files of the materials
+        pub material_libraries: Vec<String>,
+        /// The numbers of the lines that could not be read, from 1
+        pub skipped_lines: Vec<usize>,
+    }
+
+    impl ParsedObj {
+        /// Find the elements of a face corner among the ones read so far
+        fn resolve(&self, (position, texcoord, normal): RawIndex) -> Option<Index> {
+            let resolve = |index: i64, count: usize| {
+                let index = if index < 0 {count as i64 + index} else {index - 1};
+                (0..count as i64).contains(&index).then_some(index as u32)
+            };
+            Some(Index {
+                position: resolve(position, self.positions.len())?,
+                normal: match normal {Some(x) => Some(resolve(x, self.normals.len())?), None => None},
+                texcoord: match texcoord {Some(x) => Some(resolve(x, self.texcoords.len())?), None => None},
+            })
+        }
+    }
+
+    /// Index of a name in a list, where it is added if it is not there yet
+    fn find_or_push(names: &mut Vec<String>, name: &str) -> u32 {
+        match names.iter().position(|x| x == name) {
+            Some(x) => x as u32,
+            None => {
+                names.push(name.to_string());
+                names.len() as u32 - 1
+            }
+        }
     }
 
+    /// The lines that cannot be read are skipped. A line that ends with a backslash continues on the next one.
     pub fn parse_obj<B: BufRead>(obj: B) -> Result<ParsedObj, Box<dyn Error>> {
         let mut parsed_obj = ParsedObj::default();
+        parsed_obj.groups.push(String::new());
+        parsed_obj.materials.push(String::new());
+        let (mut smoothing_group, mut group, mut material) = (None, 0, 0);
         
-        for line in obj.lines() {
-            let line = line?;
-            let parsed_line = match parse_line(&line) {
+        let mut lines = obj.lines().enumerate();
+        while let Some((line_index, line)) = lines.next() {
+            let mut line = line?;
+            while let Some(continued) = line.trim_end().strip_suffix('\\') {
+                line.truncate(continued.len());
+                match lines.next() {
+                    Some((_, next)) => line += &(" ".to_string() + &next?),
+                    None => break,
+                }
+            }
+            let line_number = line_index + 1;
+            let line = super::obj::strip_comment(&line).trim();
+            if line.is_empty() {
+                continue
+            }
+
+            let parsed_line = match parse_line(line) {
                 Ok((_, parsed_line)) => parsed_line,
-                Err(_) => continue
+                Err(_) => {
+                    parsed_obj.skipped_lines.push(line_number);
+                    continue
+                }
             };
             match parsed_line {
                 Line::V(v) => parsed_obj.positions.push(v),
                 Line::Vn(vn) => parsed_obj.normals.push(vn),
                 Line::Vt(vt) => parsed_obj.texcoords.push(vt),
+                Line::F(f) if f.len() < 3 => parsed_obj.skipped_lines.push(line_number),
                 Line::F(f) => {
+                    // A face that uses an element that does not exist is skipped too
+                    let vertices = match f.into_iter().map(|x| parsed_obj.resolve(x)).collect::<Option<Vec<_>>>() {
+                        Some(vertices) => vertices,
+                        None => {
+                            parsed_obj.skipped_lines.push(line_number);
+                            continue
+                        }
+                    };
                     let first_vertex = parsed_obj.vertices.len() as _;
-                    let num_vertices = f.len() as _;
-                    parsed_obj.faces.push(Face {first_vertex, num_vertices});
-                    parsed_obj.vertices.extend(f.iter());
+                    let num_vertices = vertices.len() as _;
+                    parsed_obj.faces.push(Face {first_vertex, num_vertices, smoothing_group, group, material});
+                    parsed_obj.vertices.extend(vertices);
+                }
+                Line::S(s) => smoothing_group = Some(s),
+                Line::Group(name) => group = find_or_push(&mut parsed_obj.groups, name),
+                Line::UseMaterial(name) => material = find_or_push(&mut parsed_obj.materials, name),
+                Line::MaterialLibraries(files) => {
+                    parsed_obj.material_libraries.extend(files.iter().map(|x| x.to_string()));
                 }
             }
         }
@@ -137,48 +509,395 @@ mod obj_parser {
 
 pub mod obj {
     use super::*;
-    use std::collections::HashMap;
+    use std::collections::{HashMap, BTreeMap};
     use std::fs::File;
-    use std::io::BufReader;
-    use std::error::Error;
+    use std::io::{BufRead, BufReader};
+
+    /// Largest angle between two faces whose normals are smoothed together, for the meshes without normals
+    pub const DEFAULT_CREASE_ANGLE: Real = PI / 3.0;
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Options {
+        /// See `DEFAULT_CREASE_ANGLE`
+        pub crease_angle: Real,
+        /// Fail on the lines that cannot be read instead of skipping them
+        pub strict: bool,
+    }
+
+    impl Default for Options {
+        fn default() -> Self {
+            Options {crease_angle: DEFAULT_CREASE_ANGLE, strict: false}
+        }
+    }
+
+    /// The meshes of an OBJ file and what they need to find their materials
+    pub struct Model {
+        /// One mesh per object or group of the file and per material, named after the object or group. Their
+        /// materials are indices in `material_names` until they are imported, see `mtl::import`.
+        pub meshes: Vec<Mesh>,
+        /// The materials used by the file, the first one is unnamed for the faces without material
+        pub material_names: Vec<String>,
+        /// The MTL files that declare the materials, relative to the OBJ file
+        pub material_libraries: Vec<String>,
+    }
+
+    pub fn load(path: &str, options: &Options) -> Result<Model, Box<dyn Error>> {
+        load_from(BufReader::new(File::open(path)?), options)
+    }
 
-    pub fn load(path: &str) -> Result<Mesh, Box<dyn Error>> {
-        const DEFAULT_NORMAL: Rvec3 = vector![0.0, 0.0, 0.0];
+    /// The polygons are split into triangles. The normals that are missing from the file are computed: they are the
+    /// average of the normals of the faces around the vertex, weighted by their angles at the vertex. Only the faces
+    /// of the same smoothing group and closer than the crease angle to each other are averaged, so that the sharp
+    /// edges stay sharp.
+    pub(super) fn load_from<B: BufRead>(obj: B, options: &Options) -> Result<Model, Box<dyn Error>> {
         const DEFAULT_UV: Rvec2 = vector![0.0, 0.0];
 
-        let parsed_obj = obj_parser::parse_obj(BufReader::new(File::open(path)?))?;
+        let parsed_obj = obj_parser::parse_obj(obj)?;
+        if options.strict && !parsed_obj.skipped_lines.is_empty() {
+            return Err(skipped_lines_error(&parsed_obj.skipped_lines))
+        }
+        let smooth_normals = SmoothNormals::new(&parsed_obj, options.crease_angle);
+
+        // The faces of each mesh, sorted by group then by material
+        let mut faces_by_mesh = BTreeMap::<(u32, u32), Vec<usize>>::new();
+        for (face_id, f) in parsed_obj.faces.iter().enumerate() {
+            faces_by_mesh.entry((f.group, f.material)).or_default().push(face_id);
+        }
+
+        let mut meshes = Vec::new();
+        for ((group, material), faces) in faces_by_mesh {
+            let name = &parsed_obj.groups[group as usize];
+            let material = MaterialId(material);
+            // Vertices with the same indices but different computed normals are different vertices of the mesh
+            let mut unique_vertices = HashMap::<(obj_parser::Index, [u64; 3]), u32>::new();
+            let mut vertices = Vec::new();
+            let mut indices = Vec::new();
+
+            // Fill in the mesh's vertices and indices
+            for face_id in faces {
+                let f = &parsed_obj.faces[face_id];
+                let corners = &parsed_obj.vertices[f.first_vertex as usize..(f.first_vertex + f.num_vertices) as usize];
+                let positions = corners.iter().map(|v| parsed_obj.positions[v.position as usize].into())
+                    .collect::<Vec<Rvec3>>();
+                let normals = corners.iter().map(|v| match v.normal {
+                    Some(x) => parsed_obj.normals[x as usize].into(),
+                    None => smooth_normals.at(face_id, v.position),
+                }).collect::<Vec<Rvec3>>();
+                for k in triangulate_polygon(&positions).iter().flatten() {
+                    let (v, normal) = (corners[*k], normals[*k]);
+                    let index = *unique_vertices.entry((v, normal.map(|x| x.to_bits()).into())).or_insert_with(|| {
+                        // New vertex encountered, add it to the mesh
+                        let uv = v.texcoord.map_or(DEFAULT_UV, |x| parsed_obj.texcoords[x as usize].into());
+                        let (position, tangent) = (positions[*k], Rvec3::zeros());
+                        vertices.push(Vertex {position, normal, tangent, bitangent_sign: 1.0, uv});
+                        vertices.len() as u32 - 1
+                    });
+                    indices.push(index);
+                }
+            }
+
+            let mut mesh = Mesh::new(vertices, indices, material).with_name(name);
+            mesh.generate_tangents();
+            meshes.push(mesh);
+        }
+
+        // A file without faces still gives a mesh, an empty one
+        if meshes.is_empty() {
+            meshes.push(Mesh::new(Vec::new(), Vec::new(), MaterialId(0)));
+        }
+        let obj_parser::ParsedObj {materials: material_names, material_libraries, ..} = parsed_obj;
+        Ok(Model {meshes, material_names, material_libraries})
+    }
+
+    /// The error of a strict load, from the numbers of the lines that could not be read
+    pub(crate) fn skipped_lines_error(skipped: &[usize]) -> Box<dyn Error> {
+        let numbers = skipped.iter().take(10).map(|x| x.to_string()).collect::<Vec<_>>().join(", ");
+        let more = if skipped.len() > 10 {format!(" and {} more", skipped.len() - 10)} else {String::new()};
+        let lines = if skipped.len() == 1 {"line"} else {"lines"};
+        format!("Cannot read {} {}{}", lines, numbers, more).into()
+    }
 
-        let mut unique_vertices = HashMap::<obj_parser::Index, u32>::new();
-        let mut vertices = Vec::new();
-        let mut indices = Vec::new();
+    /// The line without its comment. A comment starts with a `#` at the start of the line or after a space, so that
+    /// the names of the files and materials can have one.
+    pub(crate) fn strip_comment(line: &str) -> &str {
+        let comment = line.char_indices()
+            .find(|&(index, c)| c == '#' && line[..index].chars().next_back().is_none_or(char::is_whitespace));
+        comment.map_or(line, |(index, _)| &line[..index])
+    }
+
+    /// The faces around each position of an OBJ file, to compute the normals of its vertices
+    struct SmoothNormals<'a> {
+        parsed_obj: &'a obj_parser::ParsedObj,
+        cos_crease_angle: Real,
+        face_normals: Vec<Rvec3>,
+        /// The faces around each position, with their angle at the position
+        faces_by_position: Vec<Vec<(usize, Real)>>,
+    }
 
-        // Fill in the mesh's vertices
-        for v in parsed_obj.vertices.iter() {
-            if unique_vertices.get(&v).is_none() {
-                // New vertex encountered, add it to the mesh
-                let new_index = vertices.len() as u32;
-                unique_vertices.insert(*v, new_index);
-                let position = parsed_obj.positions[v.position as usize].into();
-                let normal = v.normal.map_or(DEFAULT_NORMAL, |x| parsed_obj.normals[x as usize].into());
-                let uv = v.texcoord.map_or(DEFAULT_UV, |x| parsed_obj.texcoords[x as usize].into());
-                vertices.push(Vertex {position, normal, uv});
+    impl<'a> SmoothNormals<'a> {
+        fn new(parsed_obj: &'a obj_parser::ParsedObj, crease_angle: Real) -> Self {
+            let mut face_normals = Vec::with_capacity(parsed_obj.faces.len());
+            let mut faces_by_position = vec![Vec::new(); parsed_obj.positions.len()];
+            for (face_id, f) in parsed_obj.faces.iter().enumerate() {
+                let corners = &parsed_obj.vertices[f.first_vertex as usize..(f.first_vertex + f.num_vertices) as usize];
+                let positions = corners.iter().map(|v| parsed_obj.positions[v.position as usize].into())
+                    .collect::<Vec<Rvec3>>();
+                face_normals.push(polygon_normal(&positions).try_normalize(SMOL).unwrap_or_else(Rvec3::zeros));
+                let n = positions.len();
+                for (k, corner) in corners.iter().enumerate() {
+                    let edges = (positions[(k + 1) % n] - positions[k], positions[(k + n - 1) % n] - positions[k]);
+                    let angle = match (edges.0.try_normalize(SMOL), edges.1.try_normalize(SMOL)) {
+                        (Some(edge1), Some(edge2)) => edge1.dot(&edge2).clamp(-1.0, 1.0).acos(),
+                        _ => 0.0,
+                    };
+                    faces_by_position[corner.position as usize].push((face_id, angle));
+                }
             }
+            SmoothNormals {parsed_obj, cos_crease_angle: crease_angle.cos(), face_normals, faces_by_position}
         }
 
-        // Fill in the mesh's indices
-        for f in parsed_obj.faces.iter() {
-            if f.num_vertices != 3 {
-                return Err("Non-triangular face are not supported".into())
+        /// The normal of a face at one of its corners
+        fn at(&self, face_id: usize, position: u32) -> Rvec3 {
+            let face_normal = self.face_normals[face_id];
+            let group = self.parsed_obj.faces[face_id].smoothing_group;
+            if group == Some(0) {
+                return face_normal
             }
-            let a = unique_vertices[&parsed_obj.vertices[f.first_vertex as usize + 0]];
-            let b = unique_vertices[&parsed_obj.vertices[f.first_vertex as usize + 1]];
-            let c = unique_vertices[&parsed_obj.vertices[f.first_vertex as usize + 2]];
-            indices.push(a);
-            indices.push(b);
-            indices.push(c);
+            let smooth = self.faces_by_position[position as usize].iter()
+                .filter(|(other, _)| {
+                    self.parsed_obj.faces[*other].smoothing_group == group
+                        && self.face_normals[*other].dot(&face_normal) >= self.cos_crease_angle
+                })
+                .map(|(other, angle)| *angle * self.face_normals[*other])
+                .sum::<Rvec3>();
+            smooth.try_normalize(SMOL).unwrap_or(face_normal)
         }
-        
-        let material = MaterialId(0);
-        Ok(Mesh {vertices, indices, material})
     }
-}
\ No newline at end of file
+
+    /// Load a model with the BVH of its meshes already built from the cache file next to the OBJ file, `mesh_id` is
+    /// the index of the first mesh in the mesh table and the others follow. The cache is made again when it is
+    /// missing or was made from another version of the OBJ file or with other options. Failing to write it is not an
+    /// error, the meshes are only loaded slower next time.
+    pub fn load_cached(path: &str, mesh_id: MeshId, strategy: BvhStrategy, options: &Options)
+        -> Result<Model, Box<dyn Error>>
+    {
+        let source = std::fs::read(path)?;
+        let source_hash = cache::hash(&source);
+        let cache_path = format!("{}.cache", path);
+
+        let cached = cache::read(&cache_path, source_hash).and_then(|bytes| {
+            let mut reader = cache::Reader::new(&bytes);
+            let cached_crease_angle = reader.real().ok()?;
+            // The file has no lines to skip if it was loaded strictly
+            let cached_strict = reader.u8().ok()? != 0;
+            let mut strings = || (0..reader.len(8).ok()?).map(|_| reader.string().ok()).collect::<Option<Vec<_>>>();
+            let (material_names, material_libraries) = (strings()?, strings()?);
+            let meshes = (0..reader.len(1).ok()?)
+                .map(|k| {
+                    let material = MaterialId(reader.u32()?);
+                    Ok(Mesh {material, ..Mesh::read_cache(&mut reader, MeshId(mesh_id.0 + k as u32))?})
+                })
+                .collect::<Result<Vec<_>, Box<dyn Error>>>().ok().filter(|_| reader.is_empty())?;
+            Some(Model {meshes, material_names, material_libraries})
+                .filter(|_| cached_crease_angle == options.crease_angle && (cached_strict || !options.strict))
+        });
+        let strategies_match = |model: &Model| {
+            model.meshes.iter().all(|x| x.bvh.as_ref().map(|bvh| bvh.strategy()) == Some(strategy))
+        };
+        if let Some(model) = cached.filter(strategies_match) {
+            return Ok(model)
+        }
+
+        let mut model = load_from(&source[..], options)?;
+        let mut writer = cache::Writer::new();
+        writer.real(options.crease_angle);
+        writer.u8(options.strict as u8);
+        for strings in [&model.material_names, &model.material_libraries] {
+            writer.len(strings.len());
+            strings.iter().for_each(|x| writer.string(x));
+        }
+        writer.len(model.meshes.len());
+        for (k, mesh) in model.meshes.iter_mut().enumerate() {
+            mesh.build_bvh(MeshId(mesh_id.0 + k as u32), strategy);
+            writer.u32(mesh.material.0);
+            mesh.write_cache(&mut writer);
+        }
+        let _ = cache::write(&cache_path, source_hash, writer);
+        Ok(model)
+    }
+}
+
+// ------------------------------------------- Tests -------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(position: Rvec3, uv: Rvec2) -> Vertex {
+        Vertex {position, normal: vector![0.0, 0.0, 1.0], tangent: Rvec3::zeros(), bitangent_sign: 1.0, uv}
+    }
+
+    /// A square in the z = 0 plane, made of two triangles
+    fn square(uvs: [Rvec2; 4]) -> Mesh {
+        let positions = [
+            vector![0.0, 0.0, 0.0], vector![1.0, 0.0, 0.0], vector![1.0, 1.0, 0.0], vector![0.0, 1.0, 0.0]
+        ];
+        let vertices = positions.iter().zip(uvs).map(|(position, uv)| vertex(*position, uv)).collect();
+        Mesh::new(vertices, vec![0, 1, 2, 0, 2, 3], MaterialId(0))
+    }
+
+    /// A cube without normals, with its faces in the given smoothing groups
+    fn cube_obj(smoothing_groups: [&str; 6]) -> String {
+        let mut obj = String::new();
+        for position in 0..8 {
+            obj += &format!("v {} {} {}\n", position & 1, (position >> 1) & 1, (position >> 2) & 1);
+        }
+        let quads = [[1, 3, 4, 2], [5, 6, 8, 7], [1, 2, 6, 5], [3, 7, 8, 4], [1, 5, 7, 3], [2, 4, 8, 6]];
+        for (quad, group) in quads.iter().zip(smoothing_groups) {
+            obj += &format!("s {}\n", group);
+            obj += &format!("f {} {} {}\nf {} {} {}\n", quad[0], quad[1], quad[2], quad[0], quad[2], quad[3]);
+        }
+        obj
+    }
+
+    fn load_cube(smoothing_groups: [&str; 6], crease_angle: Real) -> Mesh {
+        let options = obj::Options {crease_angle, strict: false};
+        obj::load_from(cube_obj(smoothing_groups).as_bytes(), &options).unwrap().meshes.remove(0)
+    }
+
+    #[test]
+    fn missing_normals_are_computed() {
+        // The edges of the cube are sharper than the crease angle
+        let mesh = load_cube(["1"; 6], obj::DEFAULT_CREASE_ANGLE);
+        assert_eq!(mesh.vertices.len(), 24);
+        for triangle in mesh.iter_triangles() {
+            let (a, b, c) = mesh.get_triangle(triangle);
+            let face_normal = (b.position - a.position).cross(&(c.position - a.position)).normalize();
+            assert!([a, b, c].iter().all(|x| (x.normal - face_normal).norm() < 1e-12));
+        }
+
+        // The corners are smoothed when the crease angle is large enough, and the triangles of a face have the same
+        // weight as the other faces thanks to the angle weighting
+        let mesh = load_cube(["1"; 6], PI);
+        assert_eq!(mesh.vertices.len(), 8);
+        for vertex in mesh.vertices.iter() {
+            let expected = (vertex.position - vector![0.5, 0.5, 0.5]).normalize();
+            assert!((vertex.normal - expected).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn smoothing_groups_are_respected() {
+        // Flat faces whatever the crease angle
+        assert_eq!(load_cube(["off"; 6], PI).vertices.len(), 24);
+        // The first four faces are smoothed together, their vertices are split from the faces along x
+        assert_eq!(load_cube(["1", "1", "1", "1", "2", "2"], PI).vertices.len(), 8 + 8);
+        assert_eq!(load_cube(["1", "1", "1", "1", "0", "0"], PI).vertices.len(), 8 + 8);
+        // No faces that share a corner are in the same group
+        assert_eq!(load_cube(["1", "1", "2", "2", "3", "3"], PI).vertices.len(), 24);
+    }
+
+    #[test]
+    fn concave_polygons_are_covered() {
+        // An L shape turning clockwise around +z, that a fan from the first corner would not cover
+        let corners = [
+            vector![1.0, 2.0, 0.0], vector![1.0, 1.0, 0.0], vector![2.0, 1.0, 0.0], vector![2.0, 0.0, 0.0],
+            vector![0.0, 0.0, 0.0], vector![0.0, 2.0, 0.0]
+        ];
+        let triangles = triangulate_polygon(&corners);
+        assert_eq!(triangles.len(), 4);
+        let mut area = 0.0;
+        for [a, b, c] in triangles {
+            let normal = (corners[b] - corners[a]).cross(&(corners[c] - corners[a]));
+            assert!(normal.z < 0.0);
+            area += normal.norm() / 2.0;
+        }
+        assert!((area - 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn obj_grammar() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+            o first # a comment\nf 1 2 3 4\n\
+            mtllib my#materials.mtl # and a comment\nusemtl red#1\n\
+            g second\nv 0 0 1\nf -4 -3 \\\n -1\n\
+            o first\nf 1 3 4\n";
+        let model = obj::load_from(obj.as_bytes(), &obj::Options::default()).unwrap();
+        assert_eq!((model.material_names, model.material_libraries), (vec!["".to_string(), "red#1".to_string()],
+            vec!["my#materials.mtl".to_string()]));
+        let meshes = model.meshes;
+        let summary = meshes.iter().map(|x| (x.name.as_str(), x.material.0, x.indices.len() / 3)).collect::<Vec<_>>();
+        assert_eq!(summary, [("first", 0, 2), ("first", 1, 1), ("second", 1, 1)]);
+        let (a, b, c) = meshes[2].get_triangle(TriangleId(0));
+        let expected = [vector![1.0, 0.0, 0.0], vector![1.0, 1.0, 0.0], vector![0.0, 0.0, 1.0]];
+        assert_eq!([a.position, b.position, c.position], expected);
+
+        // The lines that cannot be read are only errors in strict mode
+        let obj = "v 0 0 0\nv 1 0 0 1\nv 0 1 0 0.5 0.5 0.5\nvt 0 1 0\nl 1 2\nf 1 2 3\nf 1 2\n\
+            f 1 2 3 4.5\nv 1 2 3 junk\ns 1 x\n";
+        assert_eq!(obj::load_from(obj.as_bytes(), &obj::Options::default()).unwrap().meshes[0].indices.len(), 3);
+        let strict = obj::Options {strict: true, ..Default::default()};
+        let error = obj::load_from(obj.as_bytes(), &strict).err().unwrap().to_string();
+        assert_eq!(error, "Cannot read lines 5, 7, 8, 9, 10");
+
+        // So are the faces that use an element that does not exist
+        let obj = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\nf 1 -1 -4\nf 1/1 2 3\n";
+        assert_eq!(obj::load_from(obj.as_bytes(), &obj::Options::default()).unwrap().meshes[0].indices.len(), 3);
+        let error = obj::load_from(obj.as_bytes(), &strict).err().unwrap().to_string();
+        assert_eq!(error, "Cannot read lines 5, 6");
+    }
+
+    #[test]
+    fn tangents_follow_the_texture() {
+        let mut mesh = square([vector![0.0, 0.0], vector![0.0, 1.0], vector![-1.0, 1.0], vector![-1.0, 0.0]]);
+        mesh.generate_tangents();
+        assert_eq!(mesh.vertices.len(), 4);
+        for vertex in mesh.vertices.iter() {
+            // u increases toward -y and v toward +x, which is the texture turned by a quarter
+            assert!((vertex.tangent - vector![0.0, -1.0, 0.0]).norm() < 1e-12);
+            assert_eq!(vertex.bitangent_sign, 1.0);
+        }
+    }
+
+    #[test]
+    fn mirrored_vertices_are_split() {
+        // The second triangle has its texture mirrored across the diagonal of the square
+        let mut mesh = Mesh::new(vec![
+            vertex(vector![0.0, 0.0, 0.0], vector![0.0, 0.0]),
+            vertex(vector![1.0, 0.0, 0.0], vector![1.0, 0.0]),
+            vertex(vector![1.0, 1.0, 0.0], vector![1.0, 1.0]),
+            vertex(vector![0.0, 1.0, 0.0], vector![1.0, 0.0]),
+        ], vec![0, 1, 2, 0, 2, 3], MaterialId(0));
+        mesh.generate_tangents();
+
+        // The vertices of the diagonal are copied for the mirrored triangle
+        assert_eq!(mesh.vertices.len(), 6);
+        let (a, b, c) = mesh.get_triangle(TriangleId(0));
+        for vertex in [a, b, c] {
+            assert!((vertex.tangent - vector![1.0, 0.0, 0.0]).norm() < 1e-12);
+            assert_eq!(vertex.bitangent_sign, 1.0);
+        }
+        let (a, b, c) = mesh.get_triangle(TriangleId(3));
+        for vertex in [a, b, c] {
+            assert!((vertex.tangent - vector![0.0, 1.0, 0.0]).norm() < 1e-12);
+            assert_eq!(vertex.bitangent_sign, -1.0);
+        }
+    }
+
+    #[test]
+    fn degenerate_triangles_take_the_tangents_of_their_neighbors() {
+        let mut mesh = square([vector![0.0, 0.0], vector![1.0, 0.0], vector![1.0, 1.0], vector![0.0, 1.0]]);
+        // A triangle without texture, over the first one
+        let degenerate = mesh.vertices.len() as u32;
+        for k in 0..3 {
+            let position = mesh.vertices[k].position;
+            mesh.vertices.push(vertex(position, vector![0.5, 0.5]));
+        }
+        mesh.indices.extend([degenerate, degenerate + 1, degenerate + 2]);
+        mesh.generate_tangents();
+        for vertex in mesh.vertices.iter() {
+            assert!((vertex.tangent - vector![1.0, 0.0, 0.0]).norm() < 1e-12);
+            assert_eq!(vertex.bitangent_sign, 1.0);
+        }
+    }
+}
@@ -0,0 +1,346 @@
+/*
+In this file:
+- Parsing of the MTL material libraries of the OBJ files
+- Translation of their materials into the materials and textures of the scene
+*/
+
+use crate::utility::*;
+use crate::material::*;
+use crate::texture::{Texture, TextureId};
+use crate::render::SceneData;
+use crate::mesh::obj::{self, Model};
+use crate::image::tga;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+// ------------------------------------------- Parsing -------------------------------------------
+
+mod mtl_parser {
+    use crate::utility::*;
+    use crate::mesh::obj;
+    use std::{io::BufRead, error::Error, collections::HashMap};
+    use nom::{
+        IResult,
+        bytes::complete::tag,
+        sequence::{tuple, preceded, pair, terminated},
+        combinator::{map, map_res, opt, rest, eof},
+        character::complete::{space1, u32 as integer},
+        number::complete::double,
+        branch::alt,
+    };
+
+    /// The file of a texture map, its other options are skipped
+    #[derive(Debug, Clone)]
+    pub struct TextureMap {
+        pub file: String,
+        /// The `-bm` option of the bump maps
+        pub bump_multiplier: Real,
+    }
+
+    /// A material as it is declared, None for what is not
+    #[derive(Debug, Clone, Default)]
+    pub struct MaterialDesc {
+        pub diffuse: Option<Color>,
+        pub specular: Option<Color>,
+        pub emission: Option<Color>,
+        pub specular_exponent: Option<Real>,
+        pub refraction_index: Option<Real>,
+        /// Opacity, 0 is fully transparent
+        pub dissolve: Option<Real>,
+        pub illumination: Option<u32>,
+        pub diffuse_map: Option<TextureMap>,
+        pub bump_map: Option<TextureMap>,
+    }
+
+    #[derive(Clone)]
+    enum Line<'a> {
+        NewMaterial(&'a str),
+        Kd(Color),
+        Ks(Color),
+        Ke(Color),
+        Ns(Real),
+        Ni(Real),
+        D(Real),
+        /// Transparency, the opposite of the dissolve
+        Tr(Real),
+        Illum(u32),
+        MapKd(TextureMap),
+        MapBump(TextureMap),
+        /// The ambient color means nothing to a path tracer
+        Ka,
+    }
+
+    /// Either three components or a gray level
+    fn parse_color(input: &str) -> IResult<&str, Color> {
+        map(
+            tuple((double, opt(tuple((space1, double, space1, double))))),
+            |(r, gb)| gb.map_or(rgb(r, r, r), |(_, g, _, b)| rgb(r, g, b))
+        )(input)
+    }
+
+    /// The options come before the file, whose name may have spaces
+    fn parse_texture_map(input: &str) -> Result<TextureMap, &'static str> {
+        let mut words = input.split_whitespace().peekable();
+        let mut bump_multiplier = 1.0;
+        while let Some(option) = words.next_if(|x| x.starts_with('-')) {
+            let max_numbers = match option {
+                "-blendu" | "-blendv" | "-cc" | "-clamp" | "-imfchan" | "-type" => {
+                    words.next().ok_or("Texture option without a value")?;
+                    continue
+                }
+                "-bm" | "-boost" | "-texres" => 1,
+                "-mm" => 2,
+                "-o" | "-s" | "-t" => 3,
+                _ => return Err("Unknown texture option"),
+            };
+            let mut numbers = Vec::new();
+            while let Some(x) = words.peek().and_then(|x| x.parse().ok()).filter(|_| numbers.len() < max_numbers) {
+                numbers.push(x);
+                words.next();
+            }
+            match (option, numbers.first()) {
+                (_, None) => return Err("Texture option without a value"),
+                ("-bm", Some(x)) => bump_multiplier = *x,
+                _ => (),
+            }
+        }
+        let file = words.collect::<Vec<_>>().join(" ");
+        if file.is_empty() {
+            return Err("Texture map without a file")
+        }
+        Ok(TextureMap {file, bump_multiplier})
+    }
+
+    fn parse_line(input: &str) -> IResult<&str, Line<'_>> {
+        let color = |key| preceded(pair(tag(key), space1), parse_color);
+        let real = |key| preceded(pair(tag(key), space1), double);
+        let texture_map = |key| preceded(pair(tag(key), space1), map_res(rest, parse_texture_map));
+
+        // The whole line must be read, the lines with something left are skipped
+        terminated(alt((
+            map(preceded(pair(tag("newmtl"), space1), rest), Line::NewMaterial),
+            map(color("Kd"), Line::Kd),
+            map(color("Ks"), Line::Ks),
+            map(color("Ke"), Line::Ke),
+            map(color("Ka"), |_| Line::Ka),
+            map(real("Ns"), Line::Ns),
+            map(real("Ni"), Line::Ni),
+            map(real("d"), Line::D),
+            map(real("Tr"), Line::Tr),
+            map(preceded(pair(tag("illum"), space1), integer), Line::Illum),
+            map(texture_map("map_Kd"), Line::MapKd),
+            map(alt((texture_map("map_Bump"), texture_map("map_bump"), texture_map("bump"))), Line::MapBump),
+        )), eof)(input)
+    }
+
+    pub type Materials = HashMap<String, MaterialDesc>;
+
+    /// The materials by name, and the numbers of the lines that could not be read
+    pub fn parse_mtl<B: BufRead>(mtl: B) -> Result<(Materials, Vec<usize>), Box<dyn Error>> {
+        let mut materials = HashMap::new();
+        let mut skipped_lines = Vec::new();
+        let mut current = None;
+
+        for (line_index, line) in mtl.lines().enumerate() {
+            let line = line?;
+            let line = obj::strip_comment(&line).trim();
+            if line.is_empty() {
+                continue
+            }
+            let parsed_line = match parse_line(line) {
+                Ok((_, Line::NewMaterial(name))) => {
+                    current = Some(name.to_string());
+                    materials.insert(name.to_string(), MaterialDesc::default());
+                    continue
+                }
+                Ok((_, parsed_line)) => parsed_line,
+                Err(_) => {
+                    skipped_lines.push(line_index + 1);
+                    continue
+                }
+            };
+            // The properties before the first material belong to none
+            let material = match current.as_ref().and_then(|x| materials.get_mut(x)) {
+                Some(material) => material,
+                None => {
+                    skipped_lines.push(line_index + 1);
+                    continue
+                }
+            };
+            match parsed_line {
+                Line::NewMaterial(_) => unreachable!(),
+                Line::Kd(x) => material.diffuse = Some(x),
+                Line::Ks(x) => material.specular = Some(x),
+                Line::Ke(x) => material.emission = Some(x),
+                Line::Ns(x) => material.specular_exponent = Some(x),
+                Line::Ni(x) => material.refraction_index = Some(x),
+                Line::D(x) => material.dissolve = Some(x),
+                Line::Tr(x) => material.dissolve = Some(1.0 - x),
+                Line::Illum(x) => material.illumination = Some(x),
+                Line::MapKd(x) => material.diffuse_map = Some(x),
+                Line::MapBump(x) => material.bump_map = Some(x),
+                Line::Ka => (),
+            }
+        }
+
+        Ok((materials, skipped_lines))
+    }
+}
+
+// ------------------------------------------- Translation -------------------------------------------
+
+use mtl_parser::MaterialDesc;
+
+/// The diffuse color of the materials that have none, and of the faces without material
+const DEFAULT_DIFFUSE: Color = vector![0.8, 0.8, 0.8];
+
+/// Height of the white texels of the bump maps, before their `-bm` multiplier. The files give no unit.
+const BUMP_HEIGHT: Real = 0.01;
+
+/// Give the meshes of a model the materials declared in its MTL libraries, and add them to the scene with their
+/// textures. `obj_path` is the path of the OBJ file, the libraries are relative to it. The model must not have been
+/// imported already. Unless it is `strict`, what cannot be read is left out: the faces whose material is not found
+/// are plain gray, and the materials whose texture cannot be loaded keep their colors.
+pub fn import(model: &mut Model, obj_path: &str, scene_data: &mut SceneData, strict: bool)
+    -> Result<(), Box<dyn Error>>
+{
+    let SceneData {material_table, texture_table, ..} = scene_data;
+    let directory = Path::new(obj_path).parent().unwrap_or_else(|| Path::new(""));
+
+    // The materials of the first libraries hide the ones with the same name in the next libraries
+    let mut declared = HashMap::new();
+    for library in model.material_libraries.iter() {
+        let path = directory.join(library);
+        let (materials, skipped_lines) = match File::open(&path) {
+            Ok(file) => mtl_parser::parse_mtl(BufReader::new(file))?,
+            Err(e) if strict => return Err(format!("Cannot read \"{}\": {}", path.display(), e).into()),
+            Err(_) => continue,
+        };
+        if strict && !skipped_lines.is_empty() {
+            return Err(format!("\"{}\": {}", path.display(), obj::skipped_lines_error(&skipped_lines)).into())
+        }
+        let library_directory = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        for (name, material) in materials {
+            declared.entry(name).or_insert((material, library_directory.clone()));
+        }
+    }
+
+    // Only the materials that are used are added, once
+    let mut loaded_textures = HashMap::new();
+    let mut material_ids = vec![None; model.material_names.len()];
+    for mesh in model.meshes.iter_mut() {
+        let index = mesh.material.to_index();
+        if material_ids[index].is_none() {
+            let name = &model.material_names[index];
+            let material = match declared.get(name) {
+                Some((material, library_directory)) => translate(material, &mut |file| {
+                    load_texture(&library_directory.join(file), &mut loaded_textures, texture_table, strict)
+                })?,
+                None if strict && !name.is_empty() => return Err(format!("Unknown material \"{}\"", name).into()),
+                None => Material::new(Scatter::Lambert, Absorb::Albedo(DEFAULT_DIFFUSE), Emit::None),
+            };
+            material_ids[index] = Some(MaterialId(material_table.len() as _));
+            material_table.push(material);
+        }
+        mesh.material = material_ids[index].unwrap();
+    }
+    Ok(())
+}
+
+/// Load the image of a texture map once for all the materials that use it. None if it cannot be loaded, unless it is
+/// `strict`.
+fn load_texture(path: &Path, loaded_textures: &mut HashMap<PathBuf, TextureId>, texture_table: &mut Vec<Texture>,
+    strict: bool) -> Result<Option<TextureId>, Box<dyn Error>>
+{
+    if let Some(texture) = loaded_textures.get(path) {
+        return Ok(Some(*texture))
+    }
+    let image = match tga::load(&path.to_string_lossy()) {
+        Ok(image) => image,
+        Err(e) if strict => return Err(format!("Cannot load \"{}\": {}", path.display(), e).into()),
+        Err(_) => return Ok(None),
+    };
+    let texture = TextureId(texture_table.len() as _);
+    texture_table.push(Texture::Image(image));
+    loaded_textures.insert(path.to_path_buf(), texture);
+    Ok(Some(texture))
+}
+
+/// Gives the texture of an image file, if it can be loaded
+type TextureLoader<'a> = dyn FnMut(&str) -> Result<Option<TextureId>, Box<dyn Error>> + 'a;
+
+/// The closest material of the renderer. The illumination models choose the scattering: the reflective ones are
+/// metals whose fuzziness comes from the specular exponent, the refractive ones and the mostly transparent materials
+/// are dielectrics, and the others are Lambert.
+fn translate(material: &MaterialDesc, texture: &mut TextureLoader) -> Result<Material, Box<dyn Error>> {
+    let diffuse = material.diffuse.unwrap_or(DEFAULT_DIFFUSE);
+    let glass = (Scatter::Dielectric {refraction_index: material.refraction_index.unwrap_or(1.0)}, Absorb::WhiteBody);
+    let (scatter, absorb) = match material.illumination {
+        Some(3) | Some(5) | Some(8) => {
+            // An exponent of 0 is fully rough and the large ones are mirrors, like the Phong lobes
+            let fuzziness = (2.0 / (material.specular_exponent.unwrap_or(0.0).max(0.0) + 2.0)).sqrt();
+            (Scatter::Metal {fuzziness}, Absorb::Albedo(material.specular.unwrap_or(diffuse)))
+        }
+        Some(4) | Some(6) | Some(7) | Some(9) => glass,
+        _ if material.dissolve.is_some_and(|x| x < 0.5) => glass,
+        _ => {
+            let diffuse_map = match &material.diffuse_map {
+                Some(map) => texture(&map.file)?,
+                None => None,
+            };
+            (Scatter::Lambert, diffuse_map.map_or(Absorb::Albedo(diffuse), Absorb::AlbedoMap))
+        }
+    };
+    let emit = material.emission.filter(|x| x.max() > 0.0).map_or(Emit::None, Emit::Color);
+    let bump = match &material.bump_map {
+        Some(map) => texture(&map.file)?.map_or(Bump::None, |texture| {
+            Bump::BumpMap {texture, strength: BUMP_HEIGHT * map.bump_multiplier}
+        }),
+        None => Bump::None,
+    };
+    Ok(Material::new(scatter, absorb, emit).with_bump(bump))
+}
+
+// ------------------------------------------- Tests -------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mtl_grammar() {
+        let mtl = "Kd 1 0 0\n\
+            newmtl plain\nKa 1 1 1\nKd 0.5\nmap_Kd -s 1 1 -o 0 0 0 -clamp on my texture#1.tga # a comment\n\
+            newmtl mirror # a comment\nKs 0.9 0.8 0.7\nNs 1000\nillum 3\nmap_Bump -bm 2 bumps.tga\n\
+            newmtl glass\nNi 1.5\nTr 0.9\nmap_Kd -unknown 1 texture.tga\n\
+            Ns 10 20\nillum 2 junk\n";
+        let (materials, skipped_lines) = mtl_parser::parse_mtl(mtl.as_bytes()).unwrap();
+        assert_eq!(skipped_lines, [1, 14, 15, 16]);
+
+        let plain = &materials["plain"];
+        assert_eq!((plain.diffuse, plain.diffuse_map.as_ref().unwrap().file.as_str()), (Some(rgb(0.5, 0.5, 0.5)),
+            "my texture#1.tga"));
+        let mirror = &materials["mirror"];
+        assert_eq!((mirror.illumination, mirror.bump_map.as_ref().unwrap().bump_multiplier), (Some(3), 2.0));
+        let glass = &materials["glass"];
+        assert!((glass.dissolve.unwrap() - 0.1).abs() < 1e-12);
+        assert_eq!((glass.specular_exponent, glass.illumination), (None, None));
+
+        // The textures are made up, in the order they are asked for
+        let mut textures = Vec::new();
+        let mut texture = |file: &str| -> Result<Option<TextureId>, Box<dyn Error>> {
+            textures.push(file.to_string());
+            Ok(Some(TextureId(textures.len() as u32 - 1)))
+        };
+        let plain = translate(&materials["plain"], &mut texture).unwrap();
+        assert!(matches!((plain.scatter(), plain.absorb()), (Scatter::Lambert, Absorb::AlbedoMap(TextureId(0)))));
+        let mirror = translate(&materials["mirror"], &mut texture).unwrap();
+        assert!(matches!(mirror.scatter(), Scatter::Metal {fuzziness} if *fuzziness < 0.1));
+        assert!(matches!(mirror.bump(), Bump::BumpMap {texture: TextureId(1), ..}));
+        let glass = translate(&materials["glass"], &mut texture).unwrap();
+        assert!(matches!(glass.scatter(), Scatter::Dielectric {refraction_index} if *refraction_index == 1.5));
+    }
+}
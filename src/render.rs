@@ -1,16 +1,69 @@
 use crate::utility::*;
 use crate::randomness::*;
-use crate::hittable::Hittable;
-use crate::material::Material;
+use crate::hittable::{Hittable, Primitive};
+use crate::material::{Material, MaterialId};
 use crate::texture::Texture;
-use crate::mesh::Mesh;
+use crate::mesh::{Mesh, MeshId, Instance};
+use crate::bvh::{BvhStrategy, TraversalCount};
 use crate::material::Emit;
+use crate::light::Light;
+use crate::image::{Array2d, Tile, pfm};
+use std::error::Error;
 
 /// Global data to be shared by the rendering workers.
 pub struct SceneData {
     pub material_table: Vec<Material>,
     pub texture_table: Vec<Texture>,
     pub mesh_table: Vec<Mesh>,
+    pub instance_table: Vec<Instance>,
+    /// Primitives with a light material, see `light::collect_lights`
+    pub light_table: Vec<Light>,
+}
+
+impl SceneData {
+    /// Build the bottom level of the acceleration structure: one BVH per mesh in the local space of the mesh.
+    /// The meshes that already have a BVH are left untouched.
+    pub fn build_blas(&mut self, strategy: BvhStrategy) {
+        for (index, mesh) in self.mesh_table.iter_mut().enumerate() {
+            if mesh.bvh.is_none() {
+                mesh.build_bvh(MeshId(index as u32), strategy);
+            }
+        }
+    }
+}
+
+/// Everything needed to render an image
+pub struct Scene {
+    pub camera: Camera,
+    pub scene_data: SceneData,
+    /// The top level of the acceleration structure, usually a BVH over the instances of meshes and the spheres
+    pub root: Hittable,
+    pub background: Emit,
+}
+
+impl Scene {
+    /// Rebuild the top level of the acceleration structure after the transformations of the instances changed,
+    /// for example between two frames of an animation. The BVHs of the meshes are reused.
+    pub fn rebuild_tlas(&mut self) {
+        if let Hittable::Bvh(bvh) = &mut self.root {
+            bvh.rebuild(&self.scene_data);
+        }
+        // The lights follow the instances by themselves, they read the transformations from the instance table
+    }
+
+    /// Update the acceleration structure after the vertices of the meshes moved, for example between two frames of a
+    /// deforming animation. The BVHs are refitted, or rebuilt when refitting degraded them too much.
+    pub fn refit(&mut self) {
+        for (index, mesh) in self.scene_data.mesh_table.iter_mut().enumerate() {
+            mesh.refit_bvh(MeshId(index as u32));
+        }
+        if let Hittable::Bvh(bvh) = &mut self.root {
+            bvh.refit(&self.scene_data);
+            if bvh.needs_rebuild() {
+                bvh.rebuild(&self.scene_data);
+            }
+        }
+    }
 }
 
 // ------------------------------------------- Camera -------------------------------------------
@@ -46,10 +99,15 @@ impl Camera {
         Ray {
             direction: self.transformation.transform_vector(&direction),
             origin: self.transformation.transform_point(&origin),
-            t_min: RAY_EPSILON,
+            t_min: 0.0,
             t_max: INFINITY,
         }
     }
+
+    /// Distance of a point from the camera plane
+    pub fn depth(&self, position: &Rvec3) -> Real {
+        -self.transformation.inverse().transform_point(position).z
+    }
 }
 
 // ------------------------------------------- Image sampling -------------------------------------------
@@ -84,63 +142,326 @@ impl Multisampler {
 
 // ------------------------------------------- Main rendering -------------------------------------------
 
+#[derive(Debug, Clone)]
+pub struct PathSettings {
+    /// Hard limit on the number of rays of a path
+    pub max_bounce: usize,
+    /// Number of rays before the paths may be terminated by russian roulette
+    pub min_bounce: usize,
+}
+
 pub struct PathTraceOutput {
     pub final_color: Color,
-    pub normal: Rvec3,
     pub hit: bool,
+    // The properties of the first hit, they are meaningless when nothing was hit
+    pub albedo: Color,
+    pub normal: Rvec3,
+    pub position: Rvec3,
+    pub material: MaterialId,
+    pub primitive: Primitive,
 }
 
 // TODO: could the background be a material too?
-pub fn trace_path(scene: &Hittable, ray: &Ray, depth: usize, scene_data: &SceneData, rng: &mut Randomizer,
+pub fn trace_path(scene: &Hittable, ray: &Ray, settings: &PathSettings, scene_data: &SceneData, rng: &mut Randomizer,
     background: &Emit) -> PathTraceOutput
 {
-    assert!(depth >= 1);
-    trace_path_first(scene, ray, depth, scene_data, rng, background)
+    assert!(settings.max_bounce >= 1);
+
+    // The first ray of the path tracing provides additional noiseless data like albedo and normal
+    let mut output = PathTraceOutput {
+        final_color: rgb(0.0, 0.0, 0.0),
+        hit: false,
+        albedo: rgb(0.0, 0.0, 0.0),
+        normal: vector![0.0, 0.0, 0.0],
+        position: vector![0.0, 0.0, 0.0],
+        material: MaterialId(0),
+        primitive: Primitive::None,
+    };
+
+    let mut ray = ray.clone();
+    let mut throughput = rgb(1.0, 1.0, 1.0);
+    // When the lights were sampled at the previous bounce, the emission of a light is weighted by multiple importance
+    // sampling using the PDF of the scattered direction
+    let mut scatter_pdf = None;
+
+    for bounce in 0..settings.max_bounce {
+        let (mut hit, material_id) = match scene.hit(&ray, scene_data) {
+            Some(hit) => hit,
+            None => {
+                let background = background.evaluate(&ray, &Hit::at_infinity(&ray.direction), scene_data, rng);
+                output.final_color += throughput.component_mul(&background);
+                break
+            }
+        };
+        let material = &scene_data.material_table[material_id.to_index()];
+        material.bump().evaluate(&ray, &mut hit, scene_data, rng);
+        let mat_out = material.evaluate(&ray, &hit, scene_data, rng);
+        if bounce == 0 {
+            output.hit = true;
+            output.albedo = mat_out.absorb;
+            output.normal = hit.normal;
+            output.position = hit.position;
+            output.material = material_id;
+            output.primitive = hit.primitive;
+        }
+        let emit_weight = match (scatter_pdf, Light::from_hit(&hit, material_id, scene_data)) {
+            (Some(scatter_pdf), Some(light)) => {
+                let light_pdf = light.pdf(&ray.origin, &hit, scene_data) / scene_data.light_table.len() as Real;
+                power_heuristic(scatter_pdf, light_pdf)
+            }
+            _ => 1.0
+        };
+        output.final_color += emit_weight * throughput.component_mul(&mat_out.emit);
+
+        let scatter = match mat_out.scatter {
+            Some(scatter) => scatter,
+            None => break // Absorb
+        };
+        throughput.component_mul_assign(&mat_out.absorb);
+
+        // Bounce, the light arriving at the hit is sampled from the lights and from the scattered ray
+        let sample_lights = bounce + 1 < settings.max_bounce && !scene_data.light_table.is_empty()
+            && !material.scatter().is_specular();
+        scatter_pdf = if sample_lights {
+            let direct = sample_direct_light(scene, &ray, &hit, material, scene_data, rng);
+            output.final_color += throughput.component_mul(&direct);
+            Some(material.scatter().evaluate_direction(&ray, &hit, &scatter.direction).pdf)
+        } else {
+            None
+        };
+
+        // Russian roulette: the darker the path, the more likely it is to stop
+        if bounce + 1 >= settings.min_bounce {
+            let survival = throughput.max().min(1.0);
+            if !rng.sample(Bernoulli(survival)) {
+                break
+            }
+            throughput /= survival;
+        }
+        ray = scatter;
+    }
+
+    output
 }
 
-// The first ray of the path tracing provides additional noiseless data like albedo and normal
-fn trace_path_first(scene: &Hittable, ray: &Ray, depth: usize, scene_data: &SceneData, rng: &mut Randomizer,
-    background: &Emit) -> PathTraceOutput
-{
-    if let Some((hit, material)) = scene.hit(ray, scene_data) {
-        let mut mat_out = scene_data.material_table[material.to_index()].evaluate(ray, &hit, scene_data, rng);
-        let normal = hit.normal;
-        let final_color = mat_out.emit + mat_out.scatter.take().map_or(
-            // Absorb
-            rgb(0.0, 0.0, 0.0),
-            // Bounce
-            |scatter| mat_out.absorb.component_mul(
-                &trace_path_continue(scene, &scatter, depth-1, scene_data, rng, background)
-            )
-        );
-        PathTraceOutput {final_color, normal, hit: true}
-    } else {
-        let final_color = background.evaluate(ray, &Hit::at_infinity(&ray.direction), scene_data, rng);
-        let normal = rgb(0.0, 0.0, 0.0); // What to put here? Will advise later
-        PathTraceOutput {final_color, normal, hit: false}
-    }
-}
-
-// The rays that come after the first provide just a color
-fn trace_path_continue(scene: &Hittable, ray: &Ray, depth: usize, scene_data: &SceneData, rng: &mut Randomizer,
-    background: &Emit) -> Color
+// Next event estimation: the light arriving directly from one randomly chosen light, before absorption
+fn sample_direct_light(scene: &Hittable, incident: &Ray, hit: &Hit, material: &Material, scene_data: &SceneData,
+    rng: &mut Randomizer) -> Color
 {
-    if depth == 0 {
-        // This ray did not reach any light
+    let num_lights = scene_data.light_table.len();
+    let light = &scene_data.light_table[rng.gen_range(0..num_lights)];
+    let sample = match light.sample(&hit.position, scene_data, rng) {
+        Some(sample) => sample,
+        None => return rgb(0.0, 0.0, 0.0)
+    };
+
+    let scatter = material.scatter().evaluate_direction(incident, hit, &sample.direction);
+    if scatter.bsdf <= 0.0 {
+        return rgb(0.0, 0.0, 0.0)
+    }
+
+    // Shoot a shadow ray toward the light
+    let shadow_ray = hit.spawn_ray_to(&sample.hit);
+    if scene.occluded(&shadow_ray, scene_data) {
         return rgb(0.0, 0.0, 0.0)
     }
 
-    if let Some((hit, material)) = scene.hit(ray, scene_data) {
-        let mut mat_out = scene_data.material_table[material.to_index()].evaluate(ray, &hit, scene_data, rng);
-        mat_out.emit + mat_out.scatter.take().map_or(
-            // Absorb
-            rgb(0.0, 0.0, 0.0),
-            // Bounce
-            |scatter| mat_out.absorb.component_mul(
-                &trace_path_continue(scene, &scatter, depth-1, scene_data, rng, background)
-            )
-        )
-    } else {
-        background.evaluate(ray, &Hit::at_infinity(&ray.direction), scene_data, rng)
+    let light_pdf = sample.pdf / num_lights as Real;
+    let light_material = &scene_data.material_table[sample.material.to_index()];
+    let emit = light_material.emit().evaluate(&shadow_ray, &sample.hit, scene_data, rng);
+    emit * (power_heuristic(light_pdf, scatter.pdf) * scatter.bsdf / light_pdf)
+}
+
+/// Weight of a sample among two sampling strategies, given the PDFs of both strategies for this sample
+// https://graphics.stanford.edu/courses/cs348b-03/papers/veach-chapter9.pdf
+fn power_heuristic(pdf: Real, other_pdf: Real) -> Real {
+    let pdf2 = pdf * pdf;
+    let other_pdf2 = other_pdf * other_pdf;
+    if pdf2 + other_pdf2 <= 0.0 {
+        return 0.0
+    }
+    pdf2 / (pdf2 + other_pdf2)
+}
+
+// ------------------------------------------- Traversal heatmap -------------------------------------------
+
+/// Hit the scene with a camera ray and count the work it took instead of computing a color.
+/// The color holds the number of box tests, the number of primitive tests, and their sum.
+pub fn trace_heatmap(scene: &Hittable, ray: &Ray, scene_data: &SceneData) -> PathTraceOutput {
+    TraversalCount::take();
+    let hit = scene.hit(ray, scene_data);
+    let count = TraversalCount::take();
+    let (box_tests, primitive_tests) = (count.box_tests as Real, count.primitive_tests as Real);
+    let mut output = PathTraceOutput {
+        final_color: rgb(box_tests, primitive_tests, box_tests + primitive_tests),
+        hit: hit.is_some(),
+        albedo: rgb(0.0, 0.0, 0.0),
+        normal: vector![0.0, 0.0, 0.0],
+        position: vector![0.0, 0.0, 0.0],
+        material: MaterialId(0),
+        primitive: Primitive::None,
+    };
+    if let Some((hit, material)) = hit {
+        output.normal = hit.normal;
+        output.position = hit.position;
+        output.material = material;
+        output.primitive = hit.primitive;
+    }
+    output
+}
+
+/// Replace the counts of `trace_heatmap` by colors from blue (no work) to red (the most work of the image)
+pub fn colorize_heatmap(image: &mut Array2d<Color>) {
+    let mut max_work: Real = 1.0;
+    for j in 0..image.height() {
+        for i in 0..image.width() {
+            max_work = max_work.max(image.get(i, j).z);
+        }
+    }
+    for j in 0..image.height() {
+        for i in 0..image.width() {
+            let t = image.get(i, j).z / max_work;
+            // Jet colormap: blue, cyan, green, yellow, red
+            let channel = |center: Real| (1.5 - (4.0 * t - center).abs()).clamp(0.0, 1.0);
+            *image.get_mut(i, j) = rgb(channel(3.0), channel(2.0), channel(1.0));
+        }
+    }
+}
+
+// ------------------------------------------- Output buffers -------------------------------------------
+
+/// Arbitrary output variables = the per-pixel buffers that the renderer produces besides the color
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aov {
+    /// Absorption at the first hit
+    Albedo,
+    /// Shading normal at the first hit
+    Normal,
+    /// Distance of the first hit from the camera plane
+    Depth,
+    /// World position of the first hit
+    Position,
+    /// Index of the material of the first hit
+    MaterialId,
+    /// Hash of the primitive of the first hit, see `Primitive::id`
+    PrimitiveId,
+    /// Variance of the color of the samples
+    Variance,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 7] = [
+        Aov::Albedo, Aov::Normal, Aov::Depth, Aov::Position, Aov::MaterialId, Aov::PrimitiveId, Aov::Variance
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Albedo => "albedo",
+            Self::Normal => "normal",
+            Self::Depth => "depth",
+            Self::Position => "position",
+            Self::MaterialId => "material_id",
+            Self::PrimitiveId => "primitive_id",
+            Self::Variance => "variance",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Aov> {
+        Self::ALL.iter().copied().find(|aov| aov.name() == name)
+    }
+
+    /// Combine the samples of a pixel. Scalar values are repeated on the three channels.
+    fn evaluate(self, samples: &[PathTraceOutput], camera: &Camera) -> Rvec3 {
+        let hits = samples.iter().filter(|s| s.hit).collect::<Vec<_>>();
+        let average = |f: &dyn Fn(&PathTraceOutput) -> Rvec3| if hits.is_empty() {
+            vector![0.0, 0.0, 0.0]
+        } else {
+            hits.iter().fold(vector![0.0, 0.0, 0.0], |acc, s| acc + f(s)) / hits.len() as Real
+        };
+        // Identifiers cannot be averaged, take the first hit instead. Nothing is -1.
+        let first_id = |f: &dyn Fn(&PathTraceOutput) -> Option<u32>| {
+            let id = hits.first().and_then(|s| f(s)).map_or(-1.0, |id| id as Real);
+            vector![id, id, id]
+        };
+
+        match self {
+            Self::Albedo => average(&|s| s.albedo),
+            Self::Normal => average(&|s| s.normal),
+            Self::Depth => if hits.is_empty() {
+                Rvec3::repeat(Real::INFINITY)
+            } else {
+                average(&|s| {let depth = camera.depth(&s.position); vector![depth, depth, depth]})
+            },
+            Self::Position => average(&|s| s.position),
+            Self::MaterialId => first_id(&|s| Some(s.material.0)),
+            Self::PrimitiveId => first_id(&|s| s.primitive.id()),
+            Self::Variance => {
+                // Unbiased estimator of the variance, on each channel
+                let n = samples.len() as Real;
+                if samples.len() < 2 {
+                    return vector![0.0, 0.0, 0.0]
+                }
+                let mean = samples.iter().fold(rgb(0.0, 0.0, 0.0), |acc, s| acc + s.final_color) / n;
+                samples.iter().fold(rgb(0.0, 0.0, 0.0), |acc, s| {
+                    let diff = s.final_color - mean;
+                    acc + diff.component_mul(&diff)
+                }) / (n - 1.0)
+            }
+        }
+    }
+}
+
+/// The results of the rendering of an image or of a tile
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    pub color: Array2d<Color>,
+    /// Fraction of the samples that hit something
+    pub alpha: Array2d<Real>,
+    pub aovs: Vec<(Aov, Array2d<Rvec3>)>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, aovs: &[Aov]) -> Self {
+        Framebuffer {
+            color: Array2d::new(width, height),
+            alpha: Array2d::new(width, height),
+            aovs: aovs.iter().map(|aov| (*aov, Array2d::new(width, height))).collect(),
+        }
+    }
+
+    pub fn aov(&self, aov: Aov) -> Option<&Array2d<Rvec3>> {
+        self.aovs.iter().find(|(x, _)| *x == aov).map(|(_, buffer)| buffer)
+    }
+
+    /// Write a pixel from the samples that were traced through it
+    pub fn set_pixel(&mut self, i: u32, j: u32, samples: &[PathTraceOutput], camera: &Camera) {
+        let n = samples.len() as Real;
+        *self.color.get_mut(i, j) = samples.iter().fold(rgb(0.0, 0.0, 0.0), |acc, s| acc + s.final_color) / n;
+        *self.alpha.get_mut(i, j) = samples.iter().filter(|s| s.hit).count() as Real / n;
+        for (aov, buffer) in self.aovs.iter_mut() {
+            *buffer.get_mut(i, j) = aov.evaluate(samples, camera);
+        }
+    }
+
+    /// Copy the content of a rendered tile to its place
+    pub fn blit(&mut self, tile: &Tile, tile_buffer: &Framebuffer) {
+        for tj in 0..tile.height {
+            for ti in 0..tile.width {
+                let (i, j) = (ti + tile.offset_i, tj + tile.offset_j);
+                *self.color.get_mut(i, j) = *tile_buffer.color.get(ti, tj);
+                *self.alpha.get_mut(i, j) = *tile_buffer.alpha.get(ti, tj);
+                for ((_, buffer), (_, tile_aov)) in self.aovs.iter_mut().zip(tile_buffer.aovs.iter()) {
+                    *buffer.get_mut(i, j) = *tile_aov.get(ti, tj);
+                }
+            }
+        }
+    }
+
+    /// Save each AOV in a PFM file named `<prefix>.<aov name>.pfm`
+    pub fn save_aovs(&self, prefix: &str) -> Result<(), Box<dyn Error>> {
+        for (aov, buffer) in self.aovs.iter() {
+            pfm::save(buffer, &format!("{}.{}.pfm", prefix, aov.name()))?;
+        }
+        Ok(())
     }
 }
\ No newline at end of file
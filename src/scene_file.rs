@@ -0,0 +1,571 @@
+/*
+In this file:
+- Parsing of the scene description files
+- Building of the scene from its description
+
+A scene file has one statement per line, everything after a # outside of quotes is a comment:
+
+    camera position -1.5 1.5 2.5 target 0 0.5 0 fov 45
+    texture sky image "sky_panorama.tga"
+    texture dark solid 0.2 0.2 0.2
+    texture light solid 0.9 0.9 0.9
+    texture tiles checker dark light
+    material floor scatter lambert absorb albedo_map tiles
+    material chrome scatter metal 0.05 absorb albedo 0.8 0.8 0.8
+    material lamp scatter none absorb black emit color 10 10 10
+    mesh "bunny.obj" material chrome rotate 0 1 0 30 scale 1.2 translate 0 0.1 0
+    sphere center 0 -1000 0 radius 1000 material floor
+    background sky_sphere sky
+
+Materials may also perturb their normals with `normal_map <texture>` or `bump_map <texture> <strength>`.
+Textures and materials are referenced by name, before or after their declaration.
+Paths are relative to the scene file. A mesh file used several times is loaded once and instanced.
+Each object or group of a mesh file is a mesh of its own, they are all placed together.
+The meshes without a material have the ones of their MTL files.
+The normals missing from a mesh file are smoothed between faces closer than `crease_angle <degrees>` (60 by default).
+The lines of a mesh file that cannot be read are skipped, unless the mesh is `strict`.
+*/
+
+use crate::utility::*;
+use crate::render::{Scene, SceneData, Camera};
+use crate::hittable::Hittable;
+use crate::material::*;
+use crate::texture::*;
+use crate::mesh::*;
+use crate::mtl;
+use crate::validate::validate_scene_data;
+use crate::bvh::{Bvh, BvhStrategy};
+use crate::image::tga;
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+// ------------------------------------------- Parsing -------------------------------------------
+
+mod scene_parser {
+    use crate::utility::*;
+    use crate::material::Scatter;
+    use nom::{
+        IResult,
+        bytes::complete::{tag, take_till, take_while1},
+        sequence::{tuple, preceded, delimited, pair},
+        combinator::{map, value, verify, cut},
+        character::complete::{space1, char, i64 as integer},
+        number::complete::double,
+        multi::many0,
+        branch::alt,
+    };
+
+    // Names are kept as slices of the line so that their column can be found back to report errors
+
+    #[derive(Debug, Clone)]
+    pub enum TextureDesc<'a> {
+        DebugUVs,
+        Solid(Color),
+        Image(&'a str),
+        Checker {odd: &'a str, even: &'a str},
+        Noise {seed: isize},
+        Perlin {seed: isize},
+    }
+
+    #[derive(Debug, Clone)]
+    pub enum AbsorbDesc<'a> {
+        BlackBody,
+        WhiteBody,
+        Albedo(Color),
+        AlbedoMap(&'a str),
+    }
+
+    #[derive(Debug, Clone)]
+    pub enum EmitDesc<'a> {
+        None,
+        DebugNormals,
+        Color(Color),
+        SkyGradient,
+        SkySphere(&'a str),
+    }
+
+    #[derive(Debug, Clone)]
+    pub enum BumpDesc<'a> {
+        NormalMap(&'a str),
+        BumpMap {texture: &'a str, strength: Real},
+    }
+
+    #[derive(Debug, Clone)]
+    pub enum CameraProperty {
+        Position(Rvec3),
+        Target(Rvec3),
+        Up(Rvec3),
+        Fov(Real),
+        FocalDist(Real),
+        LensRadius(Real),
+    }
+
+    #[derive(Debug, Clone)]
+    pub enum MaterialProperty<'a> {
+        Scatter(Scatter),
+        Absorb(AbsorbDesc<'a>),
+        Emit(EmitDesc<'a>),
+        Bump(BumpDesc<'a>),
+    }
+
+    #[derive(Debug, Clone)]
+    pub enum ObjectProperty<'a> {
+        Center(Rvec3),
+        Radius(Real),
+        Material(&'a str),
+        Translate(Rvec3),
+        Rotate(Rvec3, Real),
+        Scale(Real),
+        CreaseAngle(Real),
+        Strict,
+    }
+
+    #[derive(Debug, Clone)]
+    pub enum Statement<'a> {
+        Camera(Vec<CameraProperty>),
+        Texture(&'a str, TextureDesc<'a>),
+        Material(&'a str, Vec<MaterialProperty<'a>>),
+        Sphere(Vec<ObjectProperty<'a>>),
+        Mesh(&'a str, Vec<ObjectProperty<'a>>),
+        Background(EmitDesc<'a>),
+    }
+
+    fn name(input: &str) -> IResult<&str, &str> {
+        take_while1(|c: char| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')(input)
+    }
+
+    fn keyword<'a>(keyword: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+        verify(name, move |x: &str| x == keyword)
+    }
+
+    /// A keyword followed by a value. Once the keyword is recognized, the value must be valid.
+    fn property<'a, O>(keyword_: &'static str, parser: impl FnMut(&'a str) -> IResult<&'a str, O>)
+        -> impl FnMut(&'a str) -> IResult<&'a str, O>
+    {
+        preceded(pair(keyword(keyword_), space1), cut(parser))
+    }
+
+    fn vec3(input: &str) -> IResult<&str, Rvec3> {
+        map(tuple((double, space1, double, space1, double)), |(x, _, y, _, z)| vector![x, y, z])(input)
+    }
+
+    fn string(input: &str) -> IResult<&str, &str> {
+        delimited(char('"'), take_till(|c| c == '"'), cut(tag("\"")))(input)
+    }
+
+    fn camera_property(input: &str) -> IResult<&str, CameraProperty> {
+        alt((
+            map(property("position", vec3), CameraProperty::Position),
+            map(property("target", vec3), CameraProperty::Target),
+            map(property("up", vec3), CameraProperty::Up),
+            map(property("fov", double), |x| CameraProperty::Fov(x.to_radians())),
+            map(property("focal_dist", double), CameraProperty::FocalDist),
+            map(property("lens_radius", double), CameraProperty::LensRadius),
+        ))(input)
+    }
+
+    fn texture(input: &str) -> IResult<&str, TextureDesc<'_>> {
+        alt((
+            value(TextureDesc::DebugUVs, keyword("debug_uvs")),
+            map(property("solid", vec3), TextureDesc::Solid),
+            map(property("image", string), TextureDesc::Image),
+            map(property("checker", tuple((name, space1, name))), |(odd, _, even)| TextureDesc::Checker {odd, even}),
+            map(property("noise", integer), |seed| TextureDesc::Noise {seed: seed as isize}),
+            map(property("perlin", integer), |seed| TextureDesc::Perlin {seed: seed as isize}),
+        ))(input)
+    }
+
+    fn scatter(input: &str) -> IResult<&str, Scatter> {
+        alt((
+            value(Scatter::None, keyword("none")),
+            value(Scatter::Lambert, keyword("lambert")),
+            map(property("metal", double), |fuzziness| Scatter::Metal {fuzziness}),
+            map(property("dielectric", double), |refraction_index| Scatter::Dielectric {refraction_index}),
+        ))(input)
+    }
+
+    fn absorb(input: &str) -> IResult<&str, AbsorbDesc<'_>> {
+        alt((
+            value(AbsorbDesc::BlackBody, keyword("black")),
+            value(AbsorbDesc::WhiteBody, keyword("white")),
+            map(property("albedo", vec3), AbsorbDesc::Albedo),
+            map(property("albedo_map", name), AbsorbDesc::AlbedoMap),
+        ))(input)
+    }
+
+    fn emit(input: &str) -> IResult<&str, EmitDesc<'_>> {
+        alt((
+            value(EmitDesc::None, keyword("none")),
+            value(EmitDesc::DebugNormals, keyword("debug_normals")),
+            value(EmitDesc::SkyGradient, keyword("sky_gradient")),
+            map(property("color", vec3), EmitDesc::Color),
+            map(property("sky_sphere", name), EmitDesc::SkySphere),
+        ))(input)
+    }
+
+    fn material_property(input: &str) -> IResult<&str, MaterialProperty<'_>> {
+        alt((
+            map(property("scatter", scatter), MaterialProperty::Scatter),
+            map(property("absorb", absorb), MaterialProperty::Absorb),
+            map(property("emit", emit), MaterialProperty::Emit),
+            map(property("normal_map", name), |texture| MaterialProperty::Bump(BumpDesc::NormalMap(texture))),
+            map(property("bump_map", tuple((name, space1, double))),
+                |(texture, _, strength)| MaterialProperty::Bump(BumpDesc::BumpMap {texture, strength})),
+        ))(input)
+    }
+
+    fn object_property(input: &str) -> IResult<&str, ObjectProperty<'_>> {
+        alt((
+            map(property("center", vec3), ObjectProperty::Center),
+            map(property("radius", double), ObjectProperty::Radius),
+            map(property("material", name), ObjectProperty::Material),
+            map(property("translate", vec3), ObjectProperty::Translate),
+            map(property("rotate", tuple((vec3, space1, double))),
+                |(axis, _, angle)| ObjectProperty::Rotate(axis, angle.to_radians())),
+            map(property("scale", double), ObjectProperty::Scale),
+            map(property("crease_angle", double), |x| ObjectProperty::CreaseAngle(x.to_radians())),
+            value(ObjectProperty::Strict, keyword("strict")),
+        ))(input)
+    }
+
+    pub fn parse_statement(input: &str) -> IResult<&str, Statement<'_>> {
+        alt((
+            map(preceded(keyword("camera"), cut(many0(preceded(space1, camera_property)))), Statement::Camera),
+            map(property("texture", tuple((name, space1, texture))),
+                |(name, _, texture)| Statement::Texture(name, texture)),
+            map(property("material", pair(name, many0(preceded(space1, material_property)))),
+                |(name, properties)| Statement::Material(name, properties)),
+            map(preceded(keyword("sphere"), cut(many0(preceded(space1, object_property)))), Statement::Sphere),
+            map(property("mesh", pair(string, many0(preceded(space1, object_property)))),
+                |(path, properties)| Statement::Mesh(path, properties)),
+            map(property("background", emit), Statement::Background),
+        ))(input)
+    }
+}
+
+// ------------------------------------------- Building the scene -------------------------------------------
+
+use scene_parser::*;
+
+/// An error at a position of the scene file
+fn error_at(path: &str, line: usize, column: usize, message: &str) -> Box<dyn Error> {
+    format!("{}:{}:{}: {}", path, line, column, message).into()
+}
+
+/// The line without its comment, which starts at the first # that is not in a quoted string
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..index],
+            _ => (),
+        }
+    }
+    line
+}
+
+/// Column of a slice of a line, in characters and starting at 1
+fn column(line: &str, slice: &str) -> usize {
+    line[..slice.as_ptr() as usize - line.as_ptr() as usize].chars().count() + 1
+}
+
+pub fn load(path: &str) -> Result<Scene, Box<dyn Error>> {
+    let source = std::fs::read_to_string(path).map_err(|e| format!("Cannot read \"{}\": {}", path, e))?;
+    let directory = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let relative = |file: &str| directory.join(file).to_string_lossy().into_owned();
+
+    // Parse all the lines first, so that names can be used before their declaration
+    let mut statements = Vec::new();
+    for (line_index, line) in source.lines().enumerate() {
+        let line = strip_comment(line).trim_end();
+        let content = line.trim_start();
+        if content.is_empty() {
+            continue
+        }
+        match nom::combinator::all_consuming(parse_statement)(content) {
+            Ok((_, statement)) => statements.push((line_index + 1, line, statement)),
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                let near = e.input.split_whitespace().next().unwrap_or("end of line");
+                return Err(error_at(path, line_index + 1, column(line, e.input), &format!("Unexpected \"{}\"", near)))
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    // Give an index to every name
+    let mut texture_names = HashMap::new();
+    let mut material_names = HashMap::new();
+    for (line_number, line, statement) in statements.iter() {
+        let (names, name) = match statement {
+            Statement::Texture(name, _) => (&mut texture_names, *name),
+            Statement::Material(name, _) => (&mut material_names, *name),
+            _ => continue,
+        };
+        let index = names.len() as u32;
+        if names.insert(name, index).is_some() {
+            return Err(error_at(path, *line_number, column(line, name), &format!("\"{}\" is declared twice", name)))
+        }
+    }
+
+    let mut camera = Camera {
+        aspect_ratio: 1.0,
+        fov: FRAC_PI_2,
+        focal_dist: 1.0,
+        lens_radius: 0.0,
+        transformation: Transformation::identity(),
+    };
+    let mut scene_data = SceneData {
+        material_table: Vec::new(),
+        texture_table: Vec::new(),
+        mesh_table: Vec::new(),
+        instance_table: Vec::new(),
+        light_table: Vec::new(),
+    };
+    let mut hittable_list = Vec::new();
+    let mut background = Emit::None;
+    let mut loaded_meshes = HashMap::<_, Vec<MeshId>>::new();
+    // The meshes are added to the scene after the materials and textures of the scene file, so that the ones of the
+    // MTL files come last and do not shift their indices
+    let mut loaded_models = Vec::new();
+    let mut num_meshes = 0;
+
+    for (line_number, line, statement) in statements.iter() {
+        // Find the index of a name declared in the scene file
+        let resolve = |names: &HashMap<&str, u32>, name: &str, kind: &str| names.get(name).copied().ok_or_else(||
+            error_at(path, *line_number, column(line, name), &format!("Unknown {} \"{}\"", kind, name))
+        );
+        let texture_id = |name: &str| resolve(&texture_names, name, "texture").map(TextureId);
+        let material_id = |name: &str| resolve(&material_names, name, "material").map(MaterialId);
+        let error = |message: &str| error_at(path, *line_number, column(line, line.trim_start()), message);
+        let make_emit = |emit: &EmitDesc| -> Result<Emit, Box<dyn Error>> {
+            Ok(match emit {
+                EmitDesc::None => Emit::None,
+                EmitDesc::DebugNormals => Emit::DebugNormals,
+                EmitDesc::Color(color) => Emit::Color(*color),
+                EmitDesc::SkyGradient => Emit::SkyGradient,
+                EmitDesc::SkySphere(name) => Emit::SkySphere(texture_id(name)?),
+            })
+        };
+
+        match statement {
+            Statement::Camera(properties) => {
+                let (mut position, mut target, mut up) = (vector![0.0, 0.0, 0.0], vector![0.0, 0.0, -1.0],
+                    vector![0.0, 1.0, 0.0]);
+                for property in properties {
+                    match property {
+                        CameraProperty::Position(x) => position = *x,
+                        CameraProperty::Target(x) => target = *x,
+                        CameraProperty::Up(x) => up = *x,
+                        CameraProperty::Fov(x) => camera.fov = *x,
+                        CameraProperty::FocalDist(x) => camera.focal_dist = *x,
+                        CameraProperty::LensRadius(x) => camera.lens_radius = *x,
+                    }
+                }
+                camera.transformation = Transformation::lookat(&position, &target, &up);
+            }
+            Statement::Texture(_, texture) => {
+                scene_data.texture_table.push(match texture {
+                    TextureDesc::DebugUVs => Texture::DebugUVs,
+                    TextureDesc::Solid(color) => Texture::Solid(*color),
+                    TextureDesc::Image(file) => Texture::Image(tga::load(&relative(file))
+                        .map_err(|e| error(&format!("Cannot load \"{}\": {}", file, e)))?),
+                    TextureDesc::Checker {odd, even}
+                        => Texture::Checker {odd: texture_id(odd)?, even: texture_id(even)?},
+                    TextureDesc::Noise {seed} => Texture::Noise {seed: *seed},
+                    TextureDesc::Perlin {seed} => Texture::Perlin {seed: *seed},
+                });
+            }
+            Statement::Material(_, properties) => {
+                let (mut scatter, mut absorb, mut emit) = (Scatter::Lambert, Absorb::WhiteBody, Emit::None);
+                let mut bump = Bump::None;
+                for property in properties {
+                    match property {
+                        MaterialProperty::Scatter(x) => scatter = x.clone(),
+                        MaterialProperty::Absorb(x) => absorb = match x {
+                            AbsorbDesc::BlackBody => Absorb::BlackBody,
+                            AbsorbDesc::WhiteBody => Absorb::WhiteBody,
+                            AbsorbDesc::Albedo(color) => Absorb::Albedo(*color),
+                            AbsorbDesc::AlbedoMap(name) => Absorb::AlbedoMap(texture_id(name)?),
+                        },
+                        MaterialProperty::Emit(x) => emit = make_emit(x)?,
+                        MaterialProperty::Bump(x) => bump = match x {
+                            BumpDesc::NormalMap(name) => Bump::NormalMap(texture_id(name)?),
+                            BumpDesc::BumpMap {texture, strength}
+                                => Bump::BumpMap {texture: texture_id(texture)?, strength: *strength},
+                        },
+                    }
+                }
+                scene_data.material_table.push(Material::new(scatter, absorb, emit).with_bump(bump));
+            }
+            Statement::Sphere(properties) => {
+                let (mut center, mut radius, mut material) = (vector![0.0, 0.0, 0.0], 1.0, None);
+                for property in properties {
+                    match property {
+                        ObjectProperty::Center(x) => center = *x,
+                        ObjectProperty::Radius(x) => radius = *x,
+                        ObjectProperty::Material(x) => material = Some(material_id(x)?),
+                        _ => return Err(error("Spheres only have a center, a radius and a material")),
+                    }
+                }
+                let material = material.ok_or_else(|| error("The sphere has no material"))?;
+                hittable_list.push(Hittable::Sphere {center, radius, material});
+            }
+            Statement::Mesh(file, properties) => {
+                // Each file is loaded once per options, then instanced as many times as needed
+                let file = relative(file);
+                let mut options = obj::Options::default();
+                for property in properties {
+                    match property {
+                        ObjectProperty::CreaseAngle(x) => options.crease_angle = *x,
+                        ObjectProperty::Strict => options.strict = true,
+                        _ => (),
+                    }
+                }
+                let key = (file.clone(), options.crease_angle.to_bits(), options.strict);
+                let meshes = match loaded_meshes.get(&key) {
+                    Some(meshes) => meshes.clone(),
+                    None => {
+                        let model = obj::load_cached(&file, MeshId(num_meshes), BvhStrategy::default(), &options)
+                            .map_err(|e| error(&format!("Cannot load \"{}\": {}", file, e)))?;
+                        let mesh_ids = (num_meshes..num_meshes + model.meshes.len() as u32).map(MeshId)
+                            .collect::<Vec<_>>();
+                        num_meshes += model.meshes.len() as u32;
+                        loaded_models.push((*line_number, *line, file, options.strict, model));
+                        loaded_meshes.insert(key, mesh_ids.clone());
+                        mesh_ids
+                    }
+                };
+
+                // Transformations are applied in the order they are written
+                let mut transformation = Transformation::identity();
+                let mut material = None;
+                for property in properties {
+                    let next = match property {
+                        ObjectProperty::Material(x) => {
+                            material = Some(material_id(x)?);
+                            continue
+                        }
+                        ObjectProperty::Translate(x) => Transformation::translation(x),
+                        ObjectProperty::Rotate(axis, angle) => Transformation::rotation(axis, *angle),
+                        ObjectProperty::Scale(x) => Transformation::scaling(*x),
+                        ObjectProperty::CreaseAngle(_) | ObjectProperty::Strict => continue,
+                        _ => return Err(error("Meshes only have a material, transformations and loading options")),
+                    };
+                    transformation = transformation.then(&next);
+                }
+                for mesh in meshes {
+                    let instance = Instance {material, ..Instance::new(mesh, transformation.clone())};
+                    hittable_list.push(Hittable::Instance(InstanceId(scene_data.instance_table.len() as _)));
+                    scene_data.instance_table.push(instance);
+                }
+            }
+            Statement::Background(emit) => background = make_emit(emit)?,
+        }
+    }
+
+    for (line_number, line, file, strict, mut model) in loaded_models {
+        mtl::import(&mut model, &file, &mut scene_data, strict).map_err(|e| {
+            error_at(path, line_number, column(line, line.trim_start()),
+                &format!("Cannot load the materials of \"{}\": {}", file, e))
+        })?;
+        scene_data.mesh_table.extend(model.meshes);
+    }
+
+    if hittable_list.is_empty() {
+        return Err(format!("{}: The scene is empty", path).into())
+    }
+    // Building the BVHs would panic on some of the errors, the warnings are reported with the built scene
+    let errors = validate_scene_data(&scene_data, &hittable_list, &background).into_iter()
+        .filter(|issue| issue.is_error()).map(|issue| format!("\n  {}", issue)).collect::<String>();
+    if !errors.is_empty() {
+        return Err(format!("{}: The scene is invalid:{}", path, errors).into())
+    }
+    scene_data.build_blas(BvhStrategy::default());
+    let root = Hittable::Bvh(Bvh::new(hittable_list, &scene_data, BvhStrategy::default()));
+    Ok(Scene {camera, scene_data, root, background})
+}
+
+// ------------------------------------------- Tests -------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own for the files of a test, removed with them when the test ends, even if it fails
+    struct TestDirectory(std::path::PathBuf);
+
+    impl TestDirectory {
+        fn new(name: &str) -> TestDirectory {
+            let directory = std::env::temp_dir().join(format!("raytracing2_{}_{}", name, std::process::id()));
+            std::fs::create_dir_all(&directory).unwrap();
+            TestDirectory(directory)
+        }
+    }
+
+    impl std::ops::Deref for TestDirectory {
+        type Target = std::path::Path;
+
+        fn deref(&self) -> &std::path::Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn comments_outside_quotes() {
+        assert_eq!(strip_comment("sphere radius 1 # comment"), "sphere radius 1 ");
+        assert_eq!(strip_comment("# comment \"quoted\""), "");
+        assert_eq!(strip_comment(r#"mesh "a#b.obj" scale 2 # comment"#), r#"mesh "a#b.obj" scale 2 "#);
+        assert_eq!(strip_comment(r#"mesh "a.obj" # "quoted # comment""#), r#"mesh "a.obj" "#);
+        assert_eq!(strip_comment(r#"mesh "unterminated # string"#), r#"mesh "unterminated # string"#);
+    }
+
+    #[test]
+    fn errors_are_located() {
+        let directory = TestDirectory::new("scene_errors");
+        let cases = [
+            ("material m scatter lambert\nmaterial n scatter metal x", ":2:26: Unexpected \"x\""),
+            ("  texture t checker a b", ":1:21: Unknown texture \"a\""),
+            ("texture t solid 1 1 1\n\ntexture t solid 0 0 0", ":3:9: \"t\" is declared twice"),
+            ("# The sphere\n  sphere center 0 0 0 radius 1 # no material", ":2:3: The sphere has no material"),
+            ("material m\nsphere material m scale 2", ":2:1: Spheres only have a center, a radius and a material"),
+            ("cube 1", ":1:1: Unexpected \"cube\""),
+            ("mesh \"a.obj", ":1:12: Unexpected \"end of line\""),
+            ("mesh \"été.obj\" scale x", ":1:22: Unexpected \"x\""),
+            ("mesh \"missing.obj\"", ":1:1: Cannot load"),
+            ("camera fov 45 # nothing else", ": The scene is empty"),
+        ];
+        for (source, expected) in cases {
+            let path = directory.join("errors.scene");
+            std::fs::write(&path, source).unwrap();
+            let path = path.to_str().unwrap();
+            let error = match load(path) {
+                Ok(_) => panic!("{:?} was loaded", source),
+                Err(error) => error.to_string(),
+            };
+            assert!(error.starts_with(&format!("{}{}", path, expected)), "{:?} gives {:?}", source, error);
+        }
+    }
+
+    #[test]
+    fn quoted_paths_keep_their_hash() {
+        let directory = TestDirectory::new("scene_quoted_paths");
+        std::fs::write(directory.join("a#b.obj"), "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+        let path = directory.join("quoted.scene");
+        std::fs::write(&path, concat!(
+            "material m scatter lambert # A \"quoted\" comment\n",
+            "mesh \"a#b.obj\" material m translate 0 0 -1 # The \"a#b\" mesh\n",
+        )).unwrap();
+        let scene = load(path.to_str().unwrap()).unwrap();
+        assert_eq!(scene.scene_data.mesh_table.len(), 1);
+        assert_eq!(scene.scene_data.mesh_table[0].indices.len(), 3);
+        assert_eq!(scene.scene_data.instance_table[0].transformation.position, vector![0.0, 0.0, -1.0]);
+    }
+}
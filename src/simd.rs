@@ -0,0 +1,278 @@
+/*
+In this file:
+- Intersection of a ray with four boxes or four triangles at once
+- AVX implementation on x86_64, chosen when the processor supports it, and a scalar fallback
+*/
+
+// The lanes are always f64 to be as precise as the scalar code, and Real is not always f64
+#![allow(clippy::unnecessary_cast)]
+
+use crate::utility::*;
+use crate::hittable::{intersect_triangle_positions, RayShear};
+
+// ------------------------------------------- Lanes -------------------------------------------
+
+/// Four triangles in structure of arrays: `a[axis][lane]` is a coordinate of the first corner of a triangle
+#[derive(Debug, Clone, Default)]
+pub struct Triangles4 {
+    pub a: [[Real; 4]; 3],
+    pub b: [[Real; 4]; 3],
+    pub c: [[Real; 4]; 3],
+}
+
+/// Intersections with four triangles: a mask of the triangles that are hit, their distance along the ray and the
+/// barycentric coordinates of the hits
+pub type TriangleHits4 = (u32, [Real; 4], [Real; 4], [Real; 4]);
+
+/// The operations on four boxes or triangles at once. All the implementations give the same results, even for the
+/// rays that graze the boxes or that are parallel to their sides.
+pub trait Lanes: Copy {
+    /// The boxes are in structure of arrays like `Triangles4`. Returns a mask of the boxes that are hit and the
+    /// distances at which the ray enters them, see `AABB::collide`.
+    fn collide4(self, min: &[[f32; 4]; 3], max: &[[f32; 4]; 3], ray: &RayExpanded) -> (u32, [Real; 4]);
+
+    fn intersect_triangles4(self, triangles: &Triangles4, ray: &Ray) -> TriangleHits4;
+}
+
+/// The best lanes supported by the processor
+pub fn detect() -> Option<Avx> {
+    Avx::detect()
+}
+
+// ------------------------------------------- Scalar lanes -------------------------------------------
+
+/// One lane at a time, for the processors without AVX
+#[derive(Debug, Clone, Copy)]
+pub struct Scalar;
+
+impl Lanes for Scalar {
+    fn collide4(self, min: &[[f32; 4]; 3], max: &[[f32; 4]; 3], ray: &RayExpanded) -> (u32, [Real; 4]) {
+        let mut mask = 0;
+        let mut t = [0.0; 4];
+        for lane in 0..4 {
+            let aabb = AABB {
+                min: vector![min[0][lane] as Real, min[1][lane] as Real, min[2][lane] as Real],
+                max: vector![max[0][lane] as Real, max[1][lane] as Real, max[2][lane] as Real],
+            };
+            if let Some(t_enter) = aabb.collide(ray) {
+                mask |= 1 << lane;
+                t[lane] = t_enter;
+            }
+        }
+        (mask, t)
+    }
+
+    fn intersect_triangles4(self, triangles: &Triangles4, ray: &Ray) -> TriangleHits4 {
+        let mut hits = (0, [0.0; 4], [0.0; 4], [0.0; 4]);
+        let shear = RayShear::new(&ray.direction);
+        let corner = |x: &[[Real; 4]; 3], lane: usize| vector![x[0][lane], x[1][lane], x[2][lane]];
+        for lane in 0..4 {
+            let (a, b, c) = (corner(&triangles.a, lane), corner(&triangles.b, lane), corner(&triangles.c, lane));
+            if let Some((t, u, v)) = intersect_triangle_positions(&a, &b, &c, ray, &shear) {
+                hits.0 |= 1 << lane;
+                hits.1[lane] = t;
+                hits.2[lane] = u;
+                hits.3[lane] = v;
+            }
+        }
+        hits
+    }
+}
+
+// ------------------------------------------- AVX lanes -------------------------------------------
+
+/// Four f64 lanes. It can only be made by `Avx::detect`, which proves that the processor supports AVX.
+#[derive(Debug, Clone, Copy)]
+pub struct Avx(());
+
+#[cfg(not(target_arch = "x86_64"))]
+impl Avx {
+    pub fn detect() -> Option<Avx> {
+        None
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+impl Lanes for Avx {
+    fn collide4(self, _: &[[f32; 4]; 3], _: &[[f32; 4]; 3], _: &RayExpanded) -> (u32, [Real; 4]) {
+        unreachable!()
+    }
+
+    fn intersect_triangles4(self, _: &Triangles4, _: &Ray) -> TriangleHits4 {
+        unreachable!()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx {
+    use super::*;
+    use std::arch::x86_64::*;
+
+    impl Avx {
+        pub fn detect() -> Option<Avx> {
+            if is_x86_feature_detected!("avx") {Some(Avx(()))} else {None}
+        }
+    }
+
+    impl Lanes for Avx {
+        #[inline(always)]
+        fn collide4(self, min: &[[f32; 4]; 3], max: &[[f32; 4]; 3], ray: &RayExpanded) -> (u32, [Real; 4]) {
+            // Safety: the processor supports AVX, otherwise there would be no `Avx`
+            unsafe {collide4(min, max, ray)}
+        }
+
+        #[inline(always)]
+        fn intersect_triangles4(self, triangles: &Triangles4, ray: &Ray) -> TriangleHits4 {
+            // Safety: the processor supports AVX, otherwise there would be no `Avx`
+            unsafe {intersect_triangles4(triangles, ray)}
+        }
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx")]
+    fn splat(x: Real) -> __m256d {
+        _mm256_set1_pd(x as f64)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx")]
+    fn load(x: &[Real; 4]) -> __m256d {
+        _mm256_set_pd(x[3] as f64, x[2] as f64, x[1] as f64, x[0] as f64)
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx")]
+    fn store(x: __m256d) -> [Real; 4] {
+        let mut y = [0.0f64; 4];
+        // Safety: y has room for four f64
+        unsafe {_mm256_storeu_pd(y.as_mut_ptr(), x)};
+        [y[0] as Real, y[1] as Real, y[2] as Real, y[3] as Real]
+    }
+
+    // Same operations as `AABB::collide`
+    #[inline]
+    #[target_feature(enable = "avx")]
+    fn collide4(min: &[[f32; 4]; 3], max: &[[f32; 4]; 3], ray: &RayExpanded) -> (u32, [Real; 4]) {
+        let mut t_min = splat(ray.inner.t_min);
+        let mut t_max = splat(ray.inner.t_max);
+        for axis in 0..3 {
+            // Safety: the arrays have four f32
+            let (min, max) = unsafe {(
+                _mm256_cvtps_pd(_mm_loadu_ps(min[axis].as_ptr())),
+                _mm256_cvtps_pd(_mm_loadu_ps(max[axis].as_ptr())),
+            )};
+            let origin = splat(ray.inner.origin[axis]);
+            let inv_direction = splat(ray.inv_direction[axis]);
+            let t0 = _mm256_mul_pd(_mm256_sub_pd(min, origin), inv_direction);
+            let t1 = _mm256_mul_pd(_mm256_sub_pd(max, origin), inv_direction);
+            // A side of the box through the origin of a parallel ray gives 0 * inf = NaN. `f64::min` and `f64::max`
+            // ignore a NaN operand, but the AVX ones return their second operand, so a NaN t1 is replaced by t0.
+            let t1 = _mm256_blendv_pd(t1, t0, _mm256_cmp_pd::<_CMP_UNORD_Q>(t1, t1));
+            // The second operand is returned when one is NaN, so the accumulated values are never NaN
+            t_min = _mm256_max_pd(_mm256_min_pd(t0, t1), t_min);
+            t_max = _mm256_min_pd(_mm256_max_pd(t0, t1), t_max);
+        }
+        let mask = _mm256_movemask_pd(_mm256_cmp_pd::<_CMP_GE_OQ>(t_max, t_min)) as u32;
+        (mask, store(t_min))
+    }
+
+    // Same operations as `intersect_triangle_positions`
+    #[inline]
+    #[target_feature(enable = "avx")]
+    fn intersect_triangles4(triangles: &Triangles4, ray: &Ray) -> TriangleHits4 {
+        let (add, sub, mul) = (_mm256_add_pd, _mm256_sub_pd, _mm256_mul_pd);
+        let RayShear {axes: [kx, ky, kz], shear: [sx, sy, sz]} = RayShear::new(&ray.direction);
+        let (sx, sy, sz) = (splat(sx), splat(sy), splat(sz));
+        let origin = [splat(ray.origin[kx]), splat(ray.origin[ky]), splat(ray.origin[kz])];
+        let project = |p: &[[Real; 4]; 3]| {
+            let (x, y, z) = (sub(load(&p[kx]), origin[0]), sub(load(&p[ky]), origin[1]), sub(load(&p[kz]), origin[2]));
+            (sub(x, mul(sx, z)), sub(y, mul(sy, z)), z)
+        };
+        let (ax, ay, az) = project(&triangles.a);
+        let (bx, by, bz) = project(&triangles.b);
+        let (cx, cy, cz) = project(&triangles.c);
+
+        let u = sub(mul(cx, by), mul(cy, bx));
+        let v = sub(mul(ax, cy), mul(ay, cx));
+        let w = sub(mul(bx, ay), mul(by, ax));
+        let zero = _mm256_setzero_pd();
+        let any_negative = _mm256_or_pd(_mm256_or_pd(
+            _mm256_cmp_pd::<_CMP_LT_OQ>(u, zero), _mm256_cmp_pd::<_CMP_LT_OQ>(v, zero)),
+            _mm256_cmp_pd::<_CMP_LT_OQ>(w, zero));
+        let any_positive = _mm256_or_pd(_mm256_or_pd(
+            _mm256_cmp_pd::<_CMP_GT_OQ>(u, zero), _mm256_cmp_pd::<_CMP_GT_OQ>(v, zero)),
+            _mm256_cmp_pd::<_CMP_GT_OQ>(w, zero));
+        let det = add(add(u, v), w);
+
+        let inv_det = _mm256_div_pd(splat(1.0), det);
+        let t = mul(add(add(mul(u, mul(sz, az)), mul(v, mul(sz, bz))), mul(w, mul(sz, cz))), inv_det);
+        let miss = _mm256_or_pd(_mm256_or_pd(
+            _mm256_and_pd(any_negative, any_positive),
+            _mm256_cmp_pd::<_CMP_EQ_OQ>(det, zero)),
+            _mm256_or_pd(
+                _mm256_cmp_pd::<_CMP_LT_OQ>(t, splat(ray.t_min)),
+                _mm256_cmp_pd::<_CMP_GT_OQ>(t, splat(ray.t_max))));
+        let mask = !_mm256_movemask_pd(miss) as u32 & 0b1111;
+        (mask, store(t), store(mul(v, inv_det)), store(mul(w, inv_det)))
+    }
+}
+
+// ------------------------------------------- Tests -------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::randomness::*;
+
+    /// Boxes with random sides, of which some are flat, and rays that start on their sides or that are parallel to
+    /// them, which are the rays that give NaN slabs
+    fn awkward_boxes_and_rays(rng: &mut Randomizer) -> ([[f32; 4]; 3], [[f32; 4]; 3], Vec<RayExpanded>) {
+        let (mut min, mut max) = ([[0.0; 4]; 3], [[0.0; 4]; 3]);
+        let coordinates = [-1.0, 0.0, 0.5, 1.0];
+        for axis in 0..3 {
+            for lane in 0..4 {
+                let (a, b) = (coordinates[rng.gen_range(0..4)], coordinates[rng.gen_range(0..4)]);
+                min[axis][lane] = f32::min(a, b);
+                max[axis][lane] = f32::max(a, b);
+            }
+        }
+        let rays = (0..64).map(|_| {
+            let mut origin = 2.0 * rng.sample(UnitBall);
+            let mut direction = rng.sample(UnitSphere);
+            for axis in 0..3 {
+                if rng.sample(Bernoulli(0.5)) {
+                    origin[axis] = coordinates[rng.gen_range(0..4)] as Real;
+                }
+                if rng.sample(Bernoulli(0.3)) {
+                    direction[axis] = if rng.sample(Bernoulli(0.5)) {0.0} else {-0.0};
+                }
+            }
+            let t_max = if rng.sample(Bernoulli(0.2)) {rng.sample(ClosedRange(0.0, 2.0))} else {INFINITY};
+            Ray {origin, direction, t_min: 0.0, t_max}.expand()
+        }).collect();
+        (min, max, rays)
+    }
+
+    #[test]
+    fn avx_boxes_match_scalar() {
+        let avx = match detect() {
+            Some(avx) => avx,
+            None => return,
+        };
+        let mut rng = Randomizer::from_seed([6; 32]);
+        let mut num_hits = 0;
+        for _ in 0..1000 {
+            let (min, max, rays) = awkward_boxes_and_rays(&mut rng);
+            for ray in rays.iter() {
+                let (mask, t) = Scalar.collide4(&min, &max, ray);
+                let (avx_mask, avx_t) = avx.collide4(&min, &max, ray);
+                assert_eq!(avx_mask, mask, "{:?} {:?} {:?}", min, max, ray.inner);
+                for lane in (0..4).filter(|lane| mask & (1 << lane) != 0) {
+                    assert_eq!(avx_t[lane], t[lane], "{:?} {:?} {:?}", min, max, ray.inner);
+                }
+                num_hits += mask.count_ones();
+            }
+        }
+        assert!(num_hits > 1000);
+    }
+}
@@ -11,6 +11,8 @@ In this file:
 
 // ------------------------------------------- Types and constants -------------------------------------------
 
+use crate::hittable::Primitive;
+
 pub type Real = f64; // <-- Choose here between f64 and f32
 pub use std::f64::{consts::*, INFINITY}; // <-- and here as well
 pub type Rvec2 = nalgebra::Vector2<Real>;
@@ -26,10 +28,17 @@ I do not use naglebra's fancy wrappers like Point and Unit because:
 
 pub use nalgebra::{vector, matrix};
 
-/// Nudge the start of the ray to avoid self-intersection
-pub const RAY_EPSILON: Real = 1e-3;
+/// Shadow rays stop this fraction of their length before the light, which is a surface that they must not hit
+pub const SHADOW_EPSILON: Real = 1e-4;
 pub const SMOL: Real = 1e-7;
 
+/// Bound of the relative rounding error after n operations
+// https://www.pbr-book.org/3ed-2018/Shapes/Managing_Rounding_Error
+pub fn gamma(n: u32) -> Real {
+    let n_epsilon = n as Real * Real::EPSILON * 0.5;
+    n_epsilon / (1.0 - n_epsilon)
+}
+
 /// A macro to quickly declare an index wrapper
 #[macro_export]
 macro_rules! declare_index_wrapper {
@@ -84,20 +93,83 @@ impl Ray {
 pub struct Hit {
     pub t: Real,
     pub position: Rvec3,
-    pub normal: Rvec3, // <-- Keep this vector normalized
+    /// Bound of the rounding errors on each coordinate of the position
+    pub position_error: Rvec3,
+    /// Shading normal, which may be interpolated from the vertices. Keep it normalized.
+    pub normal: Rvec3,
+    /// Normal of the actual surface, on the same side as `normal`. Keep it normalized.
+    pub geometric_normal: Rvec3,
+    /// Whether the ray comes from the side the normals point to, like the outside of a closed mesh
+    pub front_face: bool,
+    /// Directions of increasing u and v along the surface, as far as they can be perpendicular to `normal` and to
+    /// each other. Keep them normalized.
+    pub tangent: Rvec3,
+    pub bitangent: Rvec3,
     pub uv: Rvec2,
+    pub primitive: Primitive,
 }
 
 impl Hit {
     /// Pretends to hit a sphere infinitely far away with equirectangular texture coordinates
     pub fn at_infinity(direction: &Rvec3) -> Hit {
+        let (tangent, bitangent) = orthonormal_basis(direction);
         Hit {
             t: INFINITY,
-            position: direction.clone(),
-            normal: direction.clone(),
+            position: *direction,
+            position_error: Rvec3::zeros(),
+            normal: *direction,
+            geometric_normal: *direction,
+            front_face: false,
+            tangent,
+            bitangent,
             uv: vector![0.5 - direction.z.atan2(direction.x) / TAU, direction.y.asin() / PI + 0.5],
+            primitive: Primitive::None,
         }
     }
+
+    /// The shading normal turned toward the side the ray comes from
+    pub fn facing_normal(&self) -> Rvec3 {
+        if self.front_face {self.normal} else {-self.normal}
+    }
+
+    /// The geometric normal turned toward the side the ray comes from
+    pub fn facing_geometric_normal(&self) -> Rvec3 {
+        if self.front_face {self.geometric_normal} else {-self.geometric_normal}
+    }
+
+    /// A ray that leaves the surface in the given direction without hitting it again
+    pub fn spawn_ray(&self, direction: &Rvec3) -> Ray {
+        Ray {origin: self.offset_origin(direction), direction: *direction, t_min: 0.0, t_max: INFINITY}
+    }
+
+    /// A ray from this surface to the point of another surface, which stops just before it
+    pub fn spawn_ray_to(&self, target: &Hit) -> Ray {
+        let origin = self.offset_origin(&(target.position - self.position));
+        let to_target = target.offset_origin(&(origin - target.position)) - origin;
+        let distance = to_target.norm();
+        Ray {origin, direction: to_target / distance, t_min: 0.0, t_max: distance * (1.0 - SHADOW_EPSILON)}
+    }
+
+    /// Move the position along the geometric normal, out of the box of its rounding errors and on the side of the
+    /// direction. The offset does not depend on the scale of the scene, unlike a fixed distance.
+    // https://www.pbr-book.org/3ed-2018/Shapes/Managing_Rounding_Error#RobustSpawnedRayOrigins
+    fn offset_origin(&self, direction: &Rvec3) -> Rvec3 {
+        let normal = &self.geometric_normal;
+        let mut offset = normal.abs().dot(&self.position_error) * normal;
+        if direction.dot(normal) < 0.0 {
+            offset = -offset;
+        }
+        let mut origin = self.position + offset;
+        // Round away from the position, so that the rounding of the sum does not undo the offset
+        for axis in 0..3 {
+            if offset[axis] > 0.0 {
+                origin[axis] = origin[axis].next_up();
+            } else if offset[axis] < 0.0 {
+                origin[axis] = origin[axis].next_down();
+            }
+        }
+        origin
+    }
 }
 
 // ------------------------------------------- Some math -------------------------------------------
@@ -118,6 +190,18 @@ pub fn refract(incident: &Rvec3, normal: &Rvec3, eta: Real) -> Option<Rvec3> {
     }
 }
 
+/// Normal must be a unit vector, then it returns two unit vectors that complete it into an orthonormal basis
+// https://graphics.pixar.com/library/OrthonormalB/paper.pdf
+pub fn orthonormal_basis(normal: &Rvec3) -> (Rvec3, Rvec3) {
+    let sign = if normal.z >= 0.0 {1.0} else {-1.0};
+    let a = -1.0 / (sign + normal.z);
+    let b = normal.x * normal.y * a;
+    (
+        vector![1.0 + sign * normal.x * normal.x * a, sign * b, -sign * normal.x],
+        vector![b, sign + normal.y * normal.y * a, -normal.y]
+    )
+}
+
 // ------------------------------------------- Bounding boxes -------------------------------------------
 
 #[derive(Debug, Clone, Default)]
@@ -127,6 +211,25 @@ pub struct AABB {
 }
 
 impl AABB {
+    /// A box that contains nothing, the neutral element of the union
+    pub fn empty() -> AABB {
+        AABB {min: Rvec3::repeat(INFINITY), max: Rvec3::repeat(-INFINITY)}
+    }
+
+    pub fn union_point(&self, point: &Rvec3) -> AABB {
+        AABB {min: self.min.inf(point), max: self.max.sup(point)}
+    }
+
+    pub fn center(&self) -> Rvec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    /// Zero for an empty box
+    pub fn surface_area(&self) -> Real {
+        let d = (self.max - self.min).map(|x| x.max(0.0));
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
     pub fn union(&self, other: &AABB) -> AABB {
         AABB {
             min: vector![self.min.x.min(other.min.x), self.min.y.min(other.min.y), self.min.z.min(other.min.z)],
@@ -134,7 +237,8 @@ impl AABB {
         }
     }
 
-    pub fn collide(&self, ray: &RayExpanded) -> bool {
+    /// The distance at which the ray enters the box, if it does
+    pub fn collide(&self, ray: &RayExpanded) -> Option<Real> {
         // This is a hot function, optimizations are welcome
         // https://tavianator.com/2011/ray_box.html
         let t0 = (self.min - ray.inner.origin).component_mul(&ray.inv_direction);
@@ -150,44 +254,95 @@ impl AABB {
             .min(t0.y.max(t1.y))
             .min(t0.z.max(t1.z));
 
-        t_max >= t_min
+        if t_max >= t_min {Some(t_min)} else {None}
     }
 }
 
 // ------------------------------------------- Transformation -------------------------------------------
 
+/// A rotation, then a uniform scaling, then a translation
 #[derive(Debug, Clone)]
 pub struct Transformation {
-    pub orientation: Rmat3,
+    pub orientation: Rmat3, // <-- Keep this matrix a rotation
     pub position: Rvec3,
+    pub scale: Real,
 }
 
 impl Transformation {
     pub fn identity() -> Self {
         let orientation = Rmat3::identity();
         let position = Rvec3::zeros();
-        Transformation {orientation, position}
+        Transformation {orientation, position, scale: 1.0}
     }
 
     pub fn lookat(position: &Rvec3, target: &Rvec3, up: &Rvec3) -> Self {
         let z = (position - target).normalize();
         let x = up.cross(&z);
         let y = z.cross(&x);
-        Transformation {orientation: Rmat3::from_columns(&[x, y, z]), position: *position}
+        Transformation {orientation: Rmat3::from_columns(&[x, y, z]), position: *position, scale: 1.0}
+    }
+
+    /// Rotation of `angle` radians around `axis`
+    pub fn rotation(axis: &Rvec3, angle: Real) -> Self {
+        let orientation = nalgebra::Rotation3::new(axis.normalize() * angle).into_inner();
+        Transformation {orientation, position: Rvec3::zeros(), scale: 1.0}
+    }
+
+    pub fn translation(position: &Rvec3) -> Self {
+        Transformation {orientation: Rmat3::identity(), position: *position, scale: 1.0}
+    }
+
+    pub fn scaling(scale: Real) -> Self {
+        Transformation {orientation: Rmat3::identity(), position: Rvec3::zeros(), scale}
+    }
+
+    /// The transformation that applies `self` then `other`
+    pub fn then(&self, other: &Transformation) -> Self {
+        Transformation {
+            orientation: other.orientation * self.orientation,
+            position: other.transform_point(&self.position),
+            scale: other.scale * self.scale,
+        }
     }
 
     pub fn inverse(&self) -> Self {
         let inv_orientation = self.orientation.transpose();
-        let inv_position = -inv_orientation * self.position;
-        Transformation {orientation: inv_orientation, position: inv_position}
+        let inv_scale = 1.0 / self.scale;
+        let inv_position = -inv_scale * (inv_orientation * self.position);
+        Transformation {orientation: inv_orientation, position: inv_position, scale: inv_scale}
     }
 
     pub fn transform_vector(&self, vector: &Rvec3) -> Rvec3 {
-        self.orientation * vector
+        self.scale * (self.orientation * vector)
+    }
+
+    /// Directions and normals are only rotated, so that they stay unit vectors
+    pub fn transform_direction(&self, direction: &Rvec3) -> Rvec3 {
+        self.orientation * direction
     }
 
     pub fn transform_point(&self, point: &Rvec3) -> Rvec3 {
-        self.orientation * point + self.position
+        self.scale * (self.orientation * point) + self.position
+    }
+
+    /// Transform a point and the bound of its rounding errors, adding the errors of the transformation
+    pub fn transform_point_with_error(&self, point: &Rvec3, error: &Rvec3) -> (Rvec3, Rvec3) {
+        let orientation = self.orientation.abs();
+        let transformation_error = gamma(5) * (self.scale * (orientation * point.abs()) + self.position.abs());
+        let error = transformation_error + (1.0 + gamma(5)) * self.scale * (orientation * error);
+        (self.transform_point(point), error)
+    }
+
+    /// The bounding box of the transformed corners of a box
+    pub fn transform_aabb(&self, aabb: &AABB) -> AABB {
+        (0..8).fold(AABB::empty(), |acc, corner| {
+            let point = vector![
+                if corner & 1 == 0 {aabb.min.x} else {aabb.max.x},
+                if corner & 2 == 0 {aabb.min.y} else {aabb.max.y},
+                if corner & 4 == 0 {aabb.min.z} else {aabb.max.z}
+            ];
+            acc.union_point(&self.transform_point(&point))
+        })
     }
 }
 
@@ -0,0 +1,400 @@
+/*
+In this file:
+- Issues that can be found in a scene
+- Validation of a scene before building its BVHs and before rendering it
+*/
+
+use crate::utility::*;
+use crate::render::{Scene, SceneData};
+use crate::hittable::Hittable;
+use crate::material::*;
+use crate::texture::*;
+use crate::mesh::*;
+use std::fmt;
+
+// ------------------------------------------- Issues -------------------------------------------
+
+/// Something wrong in a scene. Most issues would make the workers panic or loop forever.
+#[derive(Debug, Clone)]
+pub enum Issue {
+    MissingTexture {texture: TextureId, user: String},
+    MissingMaterial {material: MaterialId, user: String},
+    MissingMesh {mesh: MeshId, user: String},
+    MissingInstance {instance: InstanceId},
+    MissingTriangle {triangle: TriangleId, mesh: MeshId},
+    MissingVertex {vertex: u32, mesh: MeshId},
+    /// The textures reference each other in a loop, the first one is referenced by the last one
+    CyclicTexture {cycle: Vec<TextureId>},
+    EmptyMesh {mesh: MeshId},
+    DegenerateTriangle {triangle: TriangleId, mesh: MeshId},
+    NotFinite {user: String},
+    /// The orientation is not a rotation, or the scale is not positive
+    InvalidTransformation {instance: InstanceId},
+    /// The mesh is instanced but `Mesh::build_bvh` was not called
+    MeshWithoutBvh {mesh: MeshId},
+}
+
+impl Issue {
+    /// Warnings do not prevent the rendering, they are only suspicious
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Self::EmptyMesh {..} | Self::DegenerateTriangle {..})
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MissingTexture {texture, user} => write!(f, "{} uses the texture #{} that does not exist",
+                user, texture.0),
+            Self::MissingMaterial {material, user} => write!(f, "{} uses the material #{} that does not exist",
+                user, material.0),
+            Self::MissingMesh {mesh, user} => write!(f, "{} uses the mesh #{} that does not exist", user, mesh.0),
+            Self::MissingInstance {instance} => write!(f, "The instance #{} does not exist", instance.0),
+            Self::MissingTriangle {triangle, mesh} => write!(f, "The triangle #{} does not exist in the mesh #{}",
+                triangle.0 / 3, mesh.0),
+            Self::MissingVertex {vertex, mesh} => write!(f, "The vertex #{} does not exist in the mesh #{}",
+                vertex, mesh.0),
+            Self::CyclicTexture {cycle} => {
+                let cycle = cycle.iter().chain(cycle.first()).map(|t| format!("#{}", t.0)).collect::<Vec<_>>();
+                write!(f, "The textures reference each other in a loop: {}", cycle.join(" -> "))
+            }
+            Self::EmptyMesh {mesh} => write!(f, "The mesh #{} has no triangles", mesh.0),
+            Self::DegenerateTriangle {triangle, mesh} => write!(f, "The triangle #{} of the mesh #{} has no area",
+                triangle.0 / 3, mesh.0),
+            Self::NotFinite {user} => write!(f, "{} has a coordinate that is infinite or not a number", user),
+            Self::InvalidTransformation {instance} => write!(f,
+                "The instance #{} is not transformed by a rotation, a positive scaling and a translation", instance.0),
+            Self::MeshWithoutBvh {mesh} => write!(f, "The mesh #{} is instanced but its BVH was not built", mesh.0),
+        }
+    }
+}
+
+// ------------------------------------------- Validation -------------------------------------------
+
+/// Find all the issues of a scene, an empty list means that the scene can be rendered safely
+pub fn validate(scene: &Scene) -> Vec<Issue> {
+    let scene_data = &scene.scene_data;
+    let mut issues = validate_scene_data(scene_data, std::slice::from_ref(&scene.root), &scene.background);
+    for instance in scene_data.instance_table.iter() {
+        let mesh = scene_data.mesh_table.get(instance.mesh.to_index());
+        if mesh.is_some_and(|mesh| mesh.bvh.is_none()) {
+            issues.push(Issue::MeshWithoutBvh {mesh: instance.mesh});
+        }
+    }
+    issues
+}
+
+/// Find the issues of the scene data and of the hittables to put in the top level BVH, before the BVHs are built,
+/// since building them with a missing mesh or vertex would panic. Whether the meshes have a BVH is not checked.
+pub fn validate_scene_data(scene_data: &SceneData, hittables: &[Hittable], background: &Emit) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    validate_textures(scene_data, &mut issues);
+    for (index, material) in scene_data.material_table.iter().enumerate() {
+        validate_material(material, &format!("The material #{}", index), scene_data, &mut issues);
+    }
+    validate_emit(background, "The background", scene_data, &mut issues);
+    for (index, mesh) in scene_data.mesh_table.iter().enumerate() {
+        validate_mesh(mesh, MeshId(index as u32), scene_data, &mut issues);
+    }
+    for (index, instance) in scene_data.instance_table.iter().enumerate() {
+        validate_instance(instance, InstanceId(index as u32), scene_data, &mut issues);
+    }
+    hittables.iter().for_each(|x| validate_hittable(x, scene_data, &mut issues));
+    issues
+}
+
+fn validate_texture_id(texture: TextureId, user: &str, scene_data: &SceneData, issues: &mut Vec<Issue>) {
+    if texture.to_index() >= scene_data.texture_table.len() {
+        issues.push(Issue::MissingTexture {texture, user: user.to_string()})
+    }
+}
+
+fn validate_material_id(material: MaterialId, user: &str, scene_data: &SceneData, issues: &mut Vec<Issue>) {
+    if material.to_index() >= scene_data.material_table.len() {
+        issues.push(Issue::MissingMaterial {material, user: user.to_string()})
+    }
+}
+
+fn validate_textures(scene_data: &SceneData, issues: &mut Vec<Issue>) {
+    for (index, texture) in scene_data.texture_table.iter().enumerate() {
+        if let Texture::Checker {odd, even} = texture {
+            let user = format!("The texture #{}", index);
+            validate_texture_id(*odd, &user, scene_data, issues);
+            validate_texture_id(*even, &user, scene_data, issues);
+        }
+    }
+
+    // Depth-first search of the loops, each loop is reported once from the texture where it is found
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum Visit {New, InProgress, Done}
+    fn visit(texture: TextureId, path: &mut Vec<TextureId>, visits: &mut [Visit], scene_data: &SceneData,
+        issues: &mut Vec<Issue>)
+    {
+        match visits.get(texture.to_index()) {
+            None | Some(Visit::Done) => return,
+            Some(Visit::InProgress) => {
+                let start = path.iter().position(|t| t.0 == texture.0).unwrap();
+                issues.push(Issue::CyclicTexture {cycle: path[start..].to_vec()});
+                return
+            }
+            Some(Visit::New) => (),
+        }
+        visits[texture.to_index()] = Visit::InProgress;
+        path.push(texture);
+        if let Texture::Checker {odd, even} = &scene_data.texture_table[texture.to_index()] {
+            visit(*odd, path, visits, scene_data, issues);
+            if even.0 != odd.0 {
+                visit(*even, path, visits, scene_data, issues);
+            }
+        }
+        path.pop();
+        visits[texture.to_index()] = Visit::Done;
+    }
+
+    let mut visits = vec![Visit::New; scene_data.texture_table.len()];
+    for index in 0..scene_data.texture_table.len() {
+        visit(TextureId(index as u32), &mut Vec::new(), &mut visits, scene_data, issues);
+    }
+}
+
+fn validate_material(material: &Material, user: &str, scene_data: &SceneData, issues: &mut Vec<Issue>) {
+    if let Absorb::AlbedoMap(texture) = material.absorb() {
+        validate_texture_id(*texture, user, scene_data, issues);
+    }
+    if let Bump::NormalMap(texture) | Bump::BumpMap {texture, ..} = material.bump() {
+        validate_texture_id(*texture, user, scene_data, issues);
+    }
+    validate_emit(material.emit(), user, scene_data, issues);
+}
+
+fn validate_emit(emit: &Emit, user: &str, scene_data: &SceneData, issues: &mut Vec<Issue>) {
+    if let Emit::SkySphere(texture) = emit {
+        validate_texture_id(*texture, user, scene_data, issues);
+    }
+}
+
+fn validate_mesh(mesh: &Mesh, mesh_id: MeshId, scene_data: &SceneData, issues: &mut Vec<Issue>) {
+    validate_material_id(mesh.material, &format!("The mesh #{}", mesh_id.0), scene_data, issues);
+    if mesh.indices.len() < 3 {
+        issues.push(Issue::EmptyMesh {mesh: mesh_id});
+    }
+    for (index, vertex) in mesh.vertices.iter().enumerate() {
+        let finite = vertex.position.iter().chain(vertex.normal.iter()).chain(vertex.tangent.iter())
+            .chain(vertex.uv.iter()).all(|x| x.is_finite());
+        if !finite {
+            issues.push(Issue::NotFinite {user: format!("The vertex #{} of the mesh #{}", index, mesh_id.0)});
+        }
+    }
+    for &vertex in mesh.indices.iter() {
+        if vertex as usize >= mesh.vertices.len() {
+            issues.push(Issue::MissingVertex {vertex, mesh: mesh_id});
+        }
+    }
+    for triangle in mesh.iter_triangles() {
+        let vertex = |k: usize| mesh.vertices.get(mesh.indices[triangle.to_index() + k] as usize);
+        if let (Some(a), Some(b), Some(c)) = (vertex(0), vertex(1), vertex(2)) {
+            let area = 0.5 * (b.position - a.position).cross(&(c.position - a.position)).norm();
+            if area < SMOL {
+                issues.push(Issue::DegenerateTriangle {triangle, mesh: mesh_id});
+            }
+        }
+    }
+}
+
+fn validate_instance(instance: &Instance, instance_id: InstanceId, scene_data: &SceneData, issues: &mut Vec<Issue>) {
+    let user = format!("The instance #{}", instance_id.0);
+    if instance.mesh.to_index() >= scene_data.mesh_table.len() {
+        issues.push(Issue::MissingMesh {mesh: instance.mesh, user: user.clone()});
+    }
+    if let Some(material) = instance.material {
+        validate_material_id(material, &user, scene_data, issues);
+    }
+    let transformation = &instance.transformation;
+    let finite = transformation.orientation.iter().chain(transformation.position.iter()).all(|x| x.is_finite());
+    if !finite || !transformation.scale.is_finite() {
+        issues.push(Issue::NotFinite {user});
+    } else {
+        let orientation = &transformation.orientation;
+        let is_rotation = (orientation.transpose() * orientation - Rmat3::identity()).abs().max() < 1e-6
+            && orientation.determinant() > 0.0;
+        if !is_rotation || transformation.scale <= 0.0 {
+            issues.push(Issue::InvalidTransformation {instance: instance_id});
+        }
+    }
+}
+
+fn validate_hittable(hittable: &Hittable, scene_data: &SceneData, issues: &mut Vec<Issue>) {
+    match hittable {
+        Hittable::Sphere {center, radius, material} => {
+            let user = format!("The sphere at ({}, {}, {})", center.x, center.y, center.z);
+            if !center.iter().all(|x| x.is_finite()) || !radius.is_finite() {
+                issues.push(Issue::NotFinite {user: user.clone()});
+            }
+            validate_material_id(*material, &user, scene_data, issues);
+        }
+        Hittable::Triangle {triangle, mesh} => match scene_data.mesh_table.get(mesh.to_index()) {
+            None => issues.push(Issue::MissingMesh {mesh: *mesh, user: "A triangle".to_string()}),
+            Some(m) => if triangle.0 % 3 != 0 || triangle.to_index() + 2 >= m.indices.len() {
+                issues.push(Issue::MissingTriangle {triangle: *triangle, mesh: *mesh});
+            }
+        },
+        Hittable::Instance(instance) => if instance.to_index() >= scene_data.instance_table.len() {
+            issues.push(Issue::MissingInstance {instance: *instance});
+        },
+        Hittable::List(list) => list.iter().for_each(|x| validate_hittable(x, scene_data, issues)),
+        Hittable::Bvh(bvh) => bvh.leaves().iter().for_each(|x| validate_hittable(x, scene_data, issues)),
+    }
+}
+
+// ------------------------------------------- Tests -------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::BvhStrategy;
+    use crate::render::Camera;
+
+    fn vertex(x: Real, y: Real) -> Vertex {
+        Vertex {position: vector![x, y, 0.0], normal: Rvec3::zeros(), tangent: Rvec3::zeros(), bitangent_sign: 1.0,
+            uv: Rvec2::zeros()}
+    }
+
+    /// A valid scene: a checkered sky, a square mesh with a material and an instance of it
+    fn scene_data() -> SceneData {
+        let vertices = vec![vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(1.0, 1.0), vertex(0.0, 1.0)];
+        SceneData {
+            material_table: vec![Material::new(Scatter::Lambert, Absorb::AlbedoMap(TextureId(2)), Emit::None)],
+            texture_table: vec![
+                Texture::Solid(rgb(0.2, 0.2, 0.2)),
+                Texture::Solid(rgb(0.8, 0.8, 0.8)),
+                Texture::Checker {odd: TextureId(0), even: TextureId(1)},
+            ],
+            mesh_table: vec![Mesh::new(vertices, vec![0, 1, 2, 0, 2, 3], MaterialId(0))],
+            instance_table: vec![Instance::new(MeshId(0), Transformation::identity())],
+            light_table: Vec::new(),
+        }
+    }
+
+    fn hittables() -> Vec<Hittable> {
+        vec![
+            Hittable::Instance(InstanceId(0)),
+            Hittable::Sphere {center: vector![0.0, -100.0, 0.0], radius: 100.0, material: MaterialId(0)},
+            Hittable::Triangle {triangle: TriangleId(3), mesh: MeshId(0)},
+        ]
+    }
+
+    /// The issues of the scene data after a change, with the hittables and a sky background
+    fn issues_with(change: impl FnOnce(&mut SceneData, &mut Vec<Hittable>)) -> Vec<Issue> {
+        let mut scene_data = scene_data();
+        let mut hittables = hittables();
+        change(&mut scene_data, &mut hittables);
+        validate_scene_data(&scene_data, &hittables, &Emit::SkySphere(TextureId(2)))
+    }
+
+    fn scene(scene_data: SceneData) -> Scene {
+        let camera = Camera {aspect_ratio: 1.0, fov: FRAC_PI_4, focal_dist: 1.0, lens_radius: 0.0,
+            transformation: Transformation::identity()};
+        Scene {camera, scene_data, root: Hittable::List(hittables()), background: Emit::SkySphere(TextureId(2))}
+    }
+
+    #[test]
+    fn valid_scene() {
+        assert!(issues_with(|_, _| ()).is_empty());
+
+        let mut scene_data = scene_data();
+        scene_data.build_blas(BvhStrategy::default());
+        assert!(validate(&scene(scene_data)).is_empty());
+    }
+
+    #[test]
+    fn missing_ids() {
+        let issues = issues_with(|scene_data, hittables| {
+            scene_data.material_table[0] = Material::new(Scatter::Lambert, Absorb::AlbedoMap(TextureId(3)), Emit::None);
+            scene_data.mesh_table[0].material = MaterialId(1);
+            scene_data.instance_table.push(Instance::new(MeshId(1), Transformation::identity()));
+            hittables.push(Hittable::Instance(InstanceId(2)));
+            hittables.push(Hittable::Triangle {triangle: TriangleId(6), mesh: MeshId(0)});
+            hittables.push(Hittable::Triangle {triangle: TriangleId(0), mesh: MeshId(1)});
+        });
+        assert!(matches!(issues[..], [
+            Issue::MissingTexture {texture: TextureId(3), ..},
+            Issue::MissingMaterial {material: MaterialId(1), ..},
+            Issue::MissingMesh {mesh: MeshId(1), ..},
+            Issue::MissingInstance {instance: InstanceId(2)},
+            Issue::MissingTriangle {triangle: TriangleId(6), mesh: MeshId(0)},
+            Issue::MissingMesh {mesh: MeshId(1), ..},
+        ]), "{:?}", issues);
+        assert!(issues.iter().all(|issue| issue.is_error()));
+
+        // In the leaves of a BVH too
+        let issues = issues_with(|scene_data, hittables| {
+            let list = vec![Hittable::Sphere {center: Rvec3::zeros(), radius: 1.0, material: MaterialId(1)}];
+            hittables.push(Hittable::Bvh(crate::bvh::Bvh::new(list, scene_data, BvhStrategy::default())));
+        });
+        assert!(matches!(issues[..], [Issue::MissingMaterial {material: MaterialId(1), ..}]), "{:?}", issues);
+    }
+
+    #[test]
+    fn broken_meshes() {
+        let issues = issues_with(|scene_data, _| {
+            scene_data.mesh_table[0].indices[5] = 4;
+            scene_data.mesh_table.push(Mesh::new(vec![vertex(0.0, 0.0)], Vec::new(), MaterialId(0)));
+            let vertices = vec![vertex(0.0, 0.0), vertex(1.0, 1.0), vertex(2.0, 2.0), vertex(Real::NAN, 0.0)];
+            scene_data.mesh_table.push(Mesh::new(vertices, vec![0, 1, 2], MaterialId(0)));
+        });
+        assert!(matches!(issues[..], [
+            Issue::MissingVertex {vertex: 4, mesh: MeshId(0)},
+            Issue::EmptyMesh {mesh: MeshId(1)},
+            Issue::NotFinite {..},
+            Issue::DegenerateTriangle {triangle: TriangleId(0), mesh: MeshId(2)},
+        ]), "{:?}", issues);
+        assert!(!issues[1].is_error() && !issues[3].is_error());
+        assert!(issues[0].is_error() && issues[2].is_error());
+        assert_eq!(issues[2].to_string(),
+            "The vertex #3 of the mesh #2 has a coordinate that is infinite or not a number");
+    }
+
+    #[test]
+    fn cyclic_textures() {
+        let issues = issues_with(|scene_data, _| {
+            // 3 -> 4 -> 5 -> 3, and 6 refers to itself and to the loop
+            scene_data.texture_table.push(Texture::Checker {odd: TextureId(0), even: TextureId(4)});
+            scene_data.texture_table.push(Texture::Checker {odd: TextureId(5), even: TextureId(1)});
+            scene_data.texture_table.push(Texture::Checker {odd: TextureId(3), even: TextureId(3)});
+            scene_data.texture_table.push(Texture::Checker {odd: TextureId(6), even: TextureId(4)});
+        });
+        assert_eq!(issues.len(), 2, "{:?}", issues);
+        let cycles = issues.iter().map(|issue| match issue {
+            Issue::CyclicTexture {cycle} => cycle.iter().map(|x| x.0).collect::<Vec<_>>(),
+            _ => panic!("{:?}", issue),
+        }).collect::<Vec<_>>();
+        assert_eq!(cycles, [vec![3, 4, 5], vec![6]]);
+        assert_eq!(issues[0].to_string(), "The textures reference each other in a loop: #3 -> #4 -> #5 -> #3");
+    }
+
+    #[test]
+    fn invalid_transformations() {
+        let issues = issues_with(|scene_data, hittables| {
+            let instance = |transformation| Instance::new(MeshId(0), transformation);
+            scene_data.instance_table.push(instance(Transformation::scaling(-1.0)));
+            scene_data.instance_table.push(instance(Transformation::translation(&vector![Real::INFINITY, 0.0, 0.0])));
+            let mut skewed = Transformation::identity();
+            skewed.orientation[(0, 1)] = 0.5;
+            scene_data.instance_table.push(instance(skewed));
+            hittables.push(Hittable::Sphere {center: Rvec3::zeros(), radius: Real::NAN, material: MaterialId(0)});
+        });
+        assert!(matches!(issues[..], [
+            Issue::InvalidTransformation {instance: InstanceId(1)},
+            Issue::NotFinite {..},
+            Issue::InvalidTransformation {instance: InstanceId(3)},
+            Issue::NotFinite {..},
+        ]), "{:?}", issues);
+    }
+
+    #[test]
+    fn meshes_without_bvh() {
+        // The BVHs are only checked when the scene is complete
+        let issues = validate(&scene(scene_data()));
+        assert!(matches!(issues[..], [Issue::MeshWithoutBvh {mesh: MeshId(0)}]), "{:?}", issues);
+    }
+}